@@ -1,100 +1,420 @@
-use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
-use cpal::{Stream};
-use tokio::{sync::{mpsc::{self, Receiver}}, task::JoinHandle};
-use tracing::{debug, error, info};
-use crate::{audio::{self, AudioPipelineError, speech_to_text::SttMessage}, global::CONFIG};
+use std::{collections::VecDeque, pin::Pin, sync::{Arc, atomic::{AtomicBool, AtomicU64, Ordering}}, time::Duration};
+use cpal::{Stream, traits::StreamTrait};
+use tokio::{sync::{mpsc::{self, Receiver}, watch}, task::JoinHandle, time::Sleep};
+use tracing::{debug, error, info, warn};
+use crate::{audio::{self, AudioBackendError, AudioPipelineError, recorder::AudioInputConfig, speech_to_text::{SttMessage, SttRequest}, transcriber::TranscribeOptions}, config::ChatChannel, global::CONFIG};
+
+/// Upper bound for the delay between attempts to reopen a lost input device.
+const MAX_RECONNECT_DELAY_SECS: u64 = 16;
+/// How often the input level is reported while recording, 200 ms.
+const LEVEL_INTERVAL_SAMPLES: usize = 3200;
+/// Minimum pre-roll in hands-free mode, speech has to be detected before recording starts.
+const HANDS_FREE_PRE_ROLL_MS: u64 = 300;
 
 pub enum CaptureMsg {
     Audio(Vec<f32>),
+    /// Audio from here on belongs to a recording, along with the pre-roll before it.
+    Start,
+    /// End the recording and transcribe it, even if no audio made it in.
     Stop,
-    Exit,
+    /// The recording already ended inside the pipeline, drop what the stages still hold of it.
+    Reset,
+    /// Drop the recorded audio without transcribing it.
+    Cancel,
+    /// The input stream was rebuilt, the resampler has to adapt to its format.
+    Reconfigure(AudioInputConfig),
+}
+
+/// Things the pipeline decided on its own, which the UI has to reflect.
+#[derive(Debug)]
+pub enum PipelineEvent {
+    /// Hands-free mode picked up speech and started recording.
+    RecordingStarted,
+    /// Recording ended without an explicit stop, audio is on its way to STT.
+    RecordingStopped,
+    /// The input device of the stream with the given id went away.
+    DeviceLost(u32),
+    /// The input stream was reopened after the device was lost.
+    DeviceRestored,
+    /// The recording hit `recording_timeout_secs` and was sent to STT.
+    RecordingTimedOut,
+    /// Whole seconds of audio recorded so far.
+    RecordingElapsed(u64),
+    /// Linear RMS and peak level of the recording over the last few hundred milliseconds.
+    InputLevel { rms: f32, peak: f32 },
 }
 
 pub struct AudioPipeline {
     is_recording: Arc<AtomicBool>,
-    resampled_tx: mpsc::Sender<CaptureMsg>,
-    _stream: Stream, // Keep alive
+    is_listening: Arc<AtomicBool>,
+    /// Bumped whenever recorded audio is thrown away, STT drops requests from older generations.
+    generation: Arc<AtomicU64>,
+    recorder_tx: mpsc::Sender<CaptureMsg>,
+    device_lost_tx: mpsc::UnboundedSender<PipelineEvent>,
+    options_tx: watch::Sender<TranscribeOptions>,
+    stream: Option<Stream>, // Keep alive, None while the device is lost
+    stream_id: u32,
+    stream_active: bool,
+    reconnect_timeout: Option<Pin<Box<Sleep>>>,
+    reconnect_attempts: u32,
 }
 
 impl AudioPipeline {
-    pub async fn new() -> Result<(Self, Receiver<SttMessage>, JoinHandle<Result<(), AudioPipelineError>>), AudioPipelineError> {
+    pub async fn new() -> Result<(Self, Receiver<SttMessage>, Receiver<PipelineEvent>, JoinHandle<Result<(), AudioPipelineError>>), AudioPipelineError> {
         let is_recording = Arc::new(AtomicBool::new(false));
-        let (stt_tx, audio_buffer_rx) = mpsc::channel::<Vec<f32>>(1);
+        let is_listening = Arc::new(AtomicBool::new(false));
+        let generation = Arc::new(AtomicU64::new(0));
+        let (stt_tx, audio_buffer_rx) = mpsc::channel::<SttRequest>(1);
+        // Partials have their own queue, so a final never has to wait behind one.
+        let (partial_tx, partial_rx) = mpsc::channel::<SttRequest>(1);
+        let (events_tx, events_rx) = mpsc::channel::<PipelineEvent>(10);
 
-        let (stream, stream_config, recorder_rx) = audio::recorder::init(is_recording.clone())?;
-        let (resampled_tx, resampled_rx, resampler_handle) = audio::resampler::init(
+        let (recorder_tx, recorder_rx) = mpsc::channel::<CaptureMsg>(10);
+        // Device loss must not be dropped when level updates fill up the events channel.
+        let (device_lost_tx, mut device_lost_rx) = mpsc::unbounded_channel::<PipelineEvent>();
+        let lost_events_tx = events_tx.clone();
+        tokio::spawn(async move {
+            while let Some(event) = device_lost_rx.recv().await {
+                if lost_events_tx.send(event).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let input = audio::recorder::InputDevice::open()?;
+        let stream_config = input.config();
+        let stream = audio::recorder::init(
+            input,
+            is_recording.clone(),
+            is_listening.clone(),
+            recorder_tx.clone(),
+            device_lost_tx.clone(),
+            0,
+        )?;
+        let (resampled_rx, resampler_handle) = audio::resampler::init(
             recorder_rx,
             stream_config.sample_rate as usize,
-            stream_config.input_channels,
         ).await?;
-        let capture_handle = init_audio_capture(
-            resampled_rx,
-            stt_tx,
+        let (dsp_rx, dsp_handle) = audio::dsp::init(resampled_rx).await?;
+        let (vad_rx, vad_handle) = audio::vad::init(
+            dsp_rx,
             is_recording.clone(),
+            is_listening.clone(),
+            events_tx.clone(),
         ).await?;
-        let (stt_rx, stt_handle) = audio::speech_to_text::init(audio_buffer_rx).await?;
+        let (options_tx, options_rx) = watch::channel(TranscribeOptions::for_channel(&CONFIG.chat_channels[0]));
+        let capture_handle = init_audio_capture(vad_rx, CaptureContext {
+            tx: stt_tx,
+            partial_tx,
+            is_recording: is_recording.clone(),
+            is_listening: is_listening.clone(),
+            generation: generation.clone(),
+            options: options_rx,
+            events_tx: events_tx.clone(),
+        }).await?;
+        let (stt_rx, stt_handle) = audio::speech_to_text::init(audio_buffer_rx, partial_rx, generation.clone()).await?;
 
         let handle = watch_audio_handles(vec![
             resampler_handle,
+            dsp_handle,
+            vad_handle,
             capture_handle,
             stt_handle,
         ]).await;
 
-        let pipeline = AudioPipeline {
+        let mut pipeline = AudioPipeline {
             is_recording,
-            resampled_tx,
-            _stream: stream,
+            is_listening,
+            generation,
+            recorder_tx,
+            device_lost_tx,
+            options_tx,
+            stream: Some(stream),
+            stream_id: 0,
+            stream_active: false,
+            reconnect_timeout: None,
+            reconnect_attempts: 0,
         };
+        pipeline.apply_stream_state();
 
-        Ok((pipeline, stt_rx, handle))
+        Ok((pipeline, stt_rx, events_rx, handle))
     }
 
     /// Start stream and accumulate resampled audio into buffer.
     /// If buffer reaches timeout size, stop recording and transcribe.
-    pub async fn start_recording(&self) {
+    pub async fn start_recording(&mut self) {
         self.is_recording.store(true, Ordering::Relaxed);
+        // Marks the start in-band, audio already in the pipeline stays pre-roll.
+        let _ = self.recorder_tx.send(CaptureMsg::Start).await;
+        self.sync_stream_state();
     }
 
     /// Stop stream, send accumulated audio_buffer to STT, and clear buffer.
-    pub async fn stop_recording_and_transcribe(&self) {
+    pub async fn stop_recording_and_transcribe(&mut self) {
+        self.is_recording.store(false, Ordering::Relaxed);
+        // Goes through the whole pipeline, so audio still in flight makes it into the recording.
+        let _ = self.recorder_tx.send(CaptureMsg::Stop).await;
+        self.sync_stream_state();
+    }
+
+    /// Stop recording and throw away the accumulated audio.
+    pub async fn cancel_recording(&mut self) {
         self.is_recording.store(false, Ordering::Relaxed);
-        let _ = self.resampled_tx.send(CaptureMsg::Stop).await;
+        // Partial transcripts of this recording may still be on their way.
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        let _ = self.recorder_tx.send(CaptureMsg::Cancel).await;
+        self.sync_stream_state();
+    }
+
+    /// Abort or ignore the transcription of everything recorded so far.
+    pub fn cancel_transcription(&mut self) {
+        self.generation.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Whether the message belongs to a recording that hasn't been discarded.
+    pub fn is_current(&self, msg: &SttMessage) -> bool {
+        msg.generation == self.generation.load(Ordering::Relaxed)
+    }
+
+    /// In hands-free mode the stream is monitored while idle and recording
+    /// starts by itself once speech is detected.
+    pub fn set_hands_free(&mut self, enabled: bool) {
+        self.is_listening.store(enabled, Ordering::Relaxed);
+        // Also catches recordings that were stopped by the pipeline itself.
+        self.sync_stream_state();
+    }
+
+    /// Apply the language settings of the channel that subsequent recordings are meant for.
+    pub fn set_chat_channel(&self, channel: &ChatChannel) {
+        self.options_tx.send_replace(TranscribeOptions::for_channel(channel));
     }
+
+    /// Reacts to events that concern the pipeline itself.
+    /// Returns the event if the UI should know about it.
+    pub fn handle_event(&mut self, event: PipelineEvent) -> Option<PipelineEvent> {
+        match event {
+            PipelineEvent::DeviceLost(stream_id) => {
+                // The broken stream keeps reporting errors until it is dropped.
+                if stream_id != self.stream_id || self.stream.is_none() {
+                    return None;
+                }
+                warn!("Audio input device lost, reconnecting...");
+                self.stream = None;
+                self.stream_active = false;
+                self.is_recording.store(false, Ordering::Relaxed);
+                let _ = self.recorder_tx.try_send(CaptureMsg::Cancel);
+                self.reconnect_attempts = 0;
+                self.schedule_reconnect();
+                Some(event)
+            },
+            PipelineEvent::RecordingTimedOut | PipelineEvent::RecordingStopped => {
+                // The recording was ended inside the pipeline, so the resampler and filters still
+                // hold its tail. Resetting from the front clears them for the next recording.
+                // Skipped if a new recording has been started in the meantime.
+                if !self.is_recording.load(Ordering::Relaxed) {
+                    let _ = self.recorder_tx.try_send(CaptureMsg::Reset);
+                }
+                self.sync_stream_state();
+                Some(event)
+            },
+            event => Some(event),
+        }
+    }
+
+    /// Resolves when it's time for the next attempt to reopen the input device.
+    pub async fn reconnect_timeout(&mut self) {
+        match &mut self.reconnect_timeout {
+            Some(t) => {
+                t.as_mut().await;
+                self.reconnect_timeout = None;
+            },
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Try to reopen the input stream, schedules another attempt on failure.
+    pub async fn reconnect(&mut self) -> Option<PipelineEvent> {
+        self.stream_id += 1;
+        let stream = match self.open_stream().await {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Failed to reopen audio input device: {}", e);
+                self.schedule_reconnect();
+                return None;
+            },
+        };
+
+        self.stream = Some(stream);
+        self.apply_stream_state();
+        info!("Audio input device reconnected.");
+
+        Some(PipelineEvent::DeviceRestored)
+    }
+
+    /// Opens the input device again and builds a new stream for it.
+    async fn open_stream(&self) -> Result<Stream, AudioBackendError> {
+        let input = audio::recorder::InputDevice::open()?;
+        // Make sure the resampler is set up for the new format before any audio arrives.
+        let _ = self.recorder_tx.send(CaptureMsg::Reconfigure(input.config())).await;
+        audio::recorder::init(
+            input,
+            self.is_recording.clone(),
+            self.is_listening.clone(),
+            self.recorder_tx.clone(),
+            self.device_lost_tx.clone(),
+            self.stream_id,
+        )
+    }
+
+    /// Whether the input stream has to run. With `release_mic_when_idle` it only
+    /// runs while recording or listening for speech in hands-free mode.
+    fn stream_needed(&self) -> bool {
+        !CONFIG.release_mic_when_idle
+            || self.is_recording.load(Ordering::Relaxed)
+            || self.is_listening.load(Ordering::Relaxed)
+    }
+
+    /// Plays or pauses the stream if its state is out of date.
+    fn sync_stream_state(&mut self) {
+        if self.stream_needed() != self.stream_active {
+            self.apply_stream_state();
+        }
+    }
+
+    /// Plays or pauses a freshly opened stream, whatever state the backend left it in.
+    fn apply_stream_state(&mut self) {
+        let active = self.stream_needed();
+        if let Err(e) = self.set_stream_active(active) {
+            error!("Audio stream error: {:?}", e);
+        }
+        // Recorded even on failure, retrying on every event would only repeat the error.
+        // The next change of the wanted state tries again.
+        self.stream_active = active;
+    }
+
+    fn set_stream_active(&self, active: bool) -> Result<(), AudioBackendError> {
+        let Some(stream) = &self.stream else { return Ok(()); };
+        if active {
+            stream.play()?;
+        } else {
+            stream.pause()?;
+        }
+        Ok(())
+    }
+
+    fn schedule_reconnect(&mut self) {
+        let delay = (1u64 << self.reconnect_attempts.min(4)).min(MAX_RECONNECT_DELAY_SECS);
+        self.reconnect_attempts += 1;
+        self.reconnect_timeout = Some(Box::pin(tokio::time::sleep(Duration::from_secs(delay))));
+    }
+}
+
+/// Everything the capture task needs to talk to the rest of the pipeline.
+struct CaptureContext {
+    /// Final recordings.
+    tx: mpsc::Sender<SttRequest>,
+    partial_tx: mpsc::Sender<SttRequest>,
+    is_recording: Arc<AtomicBool>,
+    is_listening: Arc<AtomicBool>,
+    generation: Arc<AtomicU64>,
+    options: watch::Receiver<TranscribeOptions>,
+    events_tx: mpsc::Sender<PipelineEvent>,
 }
 
 async fn init_audio_capture(
     mut rx: mpsc::Receiver<CaptureMsg>,
-    tx: mpsc::Sender<Vec<f32>>,
-    is_recording: Arc<AtomicBool>,
+    context: CaptureContext,
 ) -> Result<JoinHandle<Result<(), AudioPipelineError>>, AudioPipelineError> {
+    let CaptureContext { tx, partial_tx, is_recording, is_listening, generation, options, events_tx } = context;
     let handle = tokio::spawn(async move {
         let mut buffer = Vec::<f32>::with_capacity(16_000 * CONFIG.recording_timeout_secs as usize);
+        let partial_interval = (16 * CONFIG.partial_transcript_interval_ms) as usize;
+        let mut last_partial_len = 0;
+        let pre_roll_len = (16 * CONFIG.pre_roll_ms) as usize;
+        let hands_free_pre_roll_len = (16 * CONFIG.pre_roll_ms.max(HANDS_FREE_PRE_ROLL_MS)) as usize;
+        let mut pre_roll = VecDeque::<f32>::with_capacity(hands_free_pre_roll_len);
+        // Between a Start and the Stop, Cancel or timeout that ends the recording.
+        let mut in_recording = false;
+        // Generation at the start of the current recording, discarding it bumps the shared one.
+        let mut recording_generation = 0;
+        // Language settings of the channel that was active when the recording started.
+        let mut recording_options = options.borrow().clone();
+        let mut level = LevelMeter::default();
+        let mut elapsed_secs = None;
 
         debug!("Audio capture task started, waiting for audio data...");
-        loop {
-            if let Some(data) = rx.recv().await {
-                match data {
-                    CaptureMsg::Exit => {
-                        error!("Audio capture task received error signal, exiting...");
+        while let Some(data) = rx.recv().await {
+            match data {
+                CaptureMsg::Start => {
+                    if in_recording {
+                        continue;
+                    }
+                    debug!("Recording started with {} samples of pre-roll", pre_roll.len());
+                    in_recording = true;
+                    recording_generation = generation.load(Ordering::Relaxed);
+                    recording_options = options.borrow().clone();
+                    level = LevelMeter::default();
+                    elapsed_secs = None;
+                    buffer.extend(pre_roll.drain(..));
+                },
+                CaptureMsg::Stop => {
+                    // Already sent if the recording timed out.
+                    if !in_recording {
+                        continue;
+                    }
+                    in_recording = false;
+                    last_partial_len = 0;
+                    // Sent even if empty, STT answers that with NoSpeech and the UI moves on.
+                    if tx.send(SttRequest::new(finish_recording(&buffer), false, recording_generation, recording_options.clone())).await.is_err() {
                         break;
-                    },
-                    CaptureMsg::Stop => {
-                        if !buffer.is_empty() {
-                            if tx.send(buffer.clone()).await.is_err() {
-                                break;
-                            }
-                            buffer.clear();
+                    }
+                    buffer.clear();
+                },
+                CaptureMsg::Reset => { /* The recording has been sent already */ },
+                CaptureMsg::Reconfigure(_) => { /* Handled by the resampler */ },
+                CaptureMsg::Cancel => {
+                    debug!("Recording cancelled, discarding {} samples", buffer.len());
+                    in_recording = false;
+                    last_partial_len = 0;
+                    buffer.clear();
+                },
+                CaptureMsg::Audio(data) => {
+                    if !in_recording {
+                        let max_len = match is_listening.load(Ordering::Relaxed) {
+                            true => hands_free_pre_roll_len,
+                            false => pre_roll_len,
+                        };
+                        pre_roll.extend(&data);
+                        let excess = pre_roll.len().saturating_sub(max_len);
+                        pre_roll.drain(..excess);
+                        continue;
+                    }
+
+                    buffer.extend_from_slice(&data);
+                    if let Some((rms, peak)) = level.process(&data) {
+                        // Only informative, drop it rather than hold up the audio.
+                        let _ = events_tx.try_send(PipelineEvent::InputLevel { rms, peak });
+                    }
+                    let secs = (buffer.len() / 16_000) as u64;
+                    if elapsed_secs != Some(secs) {
+                        elapsed_secs = Some(secs);
+                        let _ = events_tx.try_send(PipelineEvent::RecordingElapsed(secs));
+                    }
+
+                    if buffer.len() >= 16_000 * CONFIG.recording_timeout_secs as usize {
+                        debug!("Buffer reached timeout size, sending to STT");
+                        is_recording.store(false, Ordering::Relaxed);
+                        in_recording = false;
+                        last_partial_len = 0;
+                        if tx.send(SttRequest::new(finish_recording(&buffer), false, recording_generation, recording_options.clone())).await.is_err() {
+                            break;
                         }
-                    },
-                    CaptureMsg::Audio(data) => {
-                        buffer.extend_from_slice(&data);
-                        if buffer.len() >= 16_000 * CONFIG.recording_timeout_secs as usize {
-                            debug!("Buffer reached timeout size, sending to STT");
-                            is_recording.store(false, Ordering::Relaxed);
-                            if tx.send(buffer.clone()).await.is_err() {
-                                break;
-                            }
-                            buffer.clear();
+                        buffer.clear();
+                        let _ = events_tx.send(PipelineEvent::RecordingTimedOut).await;
+                    } else if partial_interval > 0 && buffer.len() - last_partial_len >= partial_interval {
+                        // Skip this partial if STT is still busy with the previous one.
+                        if partial_tx.try_send(SttRequest::new(buffer.clone(), true, recording_generation, recording_options.clone())).is_ok() {
+                            last_partial_len = buffer.len();
                         }
                     }
                 }
@@ -107,6 +427,49 @@ async fn init_audio_capture(
     Ok(handle)
 }
 
+/// Accumulates RMS and peak level over `LEVEL_INTERVAL_SAMPLES`.
+#[derive(Default)]
+struct LevelMeter {
+    sum_squares: f32,
+    peak: f32,
+    samples: usize,
+}
+
+impl LevelMeter {
+    /// Returns the RMS and peak level once a full interval has been seen.
+    fn process(&mut self, samples: &[f32]) -> Option<(f32, f32)> {
+        for &sample in samples {
+            self.sum_squares += sample * sample;
+            self.peak = self.peak.max(sample.abs());
+        }
+        self.samples += samples.len();
+        if self.samples < LEVEL_INTERVAL_SAMPLES {
+            return None;
+        }
+
+        let level = ((self.sum_squares / self.samples as f32).sqrt(), self.peak);
+        *self = LevelMeter::default();
+        Some(level)
+    }
+}
+
+/// Prepares the recorded buffer for transcription.
+/// Returns an empty buffer if it is too quiet to contain speech.
+fn finish_recording(buffer: &[f32]) -> Vec<f32> {
+    if audio::vad::loudest_frame_rms(buffer) < audio::vad::db_to_linear(CONFIG.filter.min_speech_db) {
+        debug!("Recording is below min_speech_db, skipping transcription");
+        return Vec::new();
+    }
+
+    let mut buffer = if CONFIG.vad.trim_silence {
+        audio::vad::trim_silence(buffer, CONFIG.vad.threshold_db).to_vec()
+    } else {
+        buffer.to_vec()
+    };
+    audio::dsp::normalize(&mut buffer, CONFIG.dsp.normalize, CONFIG.dsp.normalize_target_db);
+    buffer
+}
+
 async fn watch_audio_handles(handles: Vec<JoinHandle<Result<(), AudioPipelineError>>>) -> JoinHandle<Result<(), AudioPipelineError>> {
     tokio::spawn(async move {
         let (completed, _index, remaining) = futures::future::select_all(handles).await;
@@ -0,0 +1,467 @@
+use std::{f32::consts::PI, sync::Arc};
+use realfft::{ComplexToReal, RealFftPlanner, RealToComplex, num_complex::Complex};
+use tokio::{sync::mpsc::{self, Receiver}, task::JoinHandle};
+use tracing::debug;
+
+use crate::{audio::{AudioPipelineError, audio_pipeline::CaptureMsg, vad::{db_to_linear, rms}}, config::{DspConfig, Normalization}, global::CONFIG};
+
+const SAMPLE_RATE: f32 = 16_000.0;
+/// Time constant of the noise gate level detector, 10 ms.
+const GATE_LEVEL_SAMPLES: f32 = 160.0;
+/// Time the noise gate stays open after the level drops, so word endings aren't cut, 200 ms.
+const GATE_HOLD_SAMPLES: usize = 3200;
+const GATE_ATTACK_SAMPLES: f32 = 16.0;
+const GATE_RELEASE_SAMPLES: f32 = 800.0;
+/// 32 ms frames with 50% overlap.
+const FFT_LEN: usize = 512;
+const HOP_LEN: usize = FFT_LEN / 2;
+/// How much of the estimated noise is removed, above 1 removes a bit more to avoid residual hiss.
+const OVER_SUBTRACTION: f32 = 1.5;
+/// Lowest gain applied to a frequency bin, fully muted bins sound unnatural.
+const SUPPRESSION_FLOOR: f32 = 0.1;
+
+/// Second order Butterworth high-pass filter.
+pub struct HighPass {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    z1: f32,
+    z2: f32,
+}
+
+impl HighPass {
+    pub fn new(cutoff_hz: f32) -> Self {
+        let w0 = 2.0 * PI * cutoff_hz / SAMPLE_RATE;
+        let cos = w0.cos();
+        let alpha = w0.sin() / (2.0 * std::f32::consts::FRAC_1_SQRT_2);
+        let a0 = 1.0 + alpha;
+        Self {
+            b0: (1.0 + cos) / 2.0 / a0,
+            b1: -(1.0 + cos) / a0,
+            b2: (1.0 + cos) / 2.0 / a0,
+            a1: -2.0 * cos / a0,
+            a2: (1.0 - alpha) / a0,
+            z1: 0.0,
+            z2: 0.0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.z1 = 0.0;
+        self.z2 = 0.0;
+    }
+
+    pub fn process(&mut self, samples: &mut [f32]) {
+        for sample in samples {
+            let x = *sample;
+            let y = self.b0 * x + self.z1;
+            self.z1 = self.b1 * x - self.a1 * y + self.z2;
+            self.z2 = self.b2 * x - self.a2 * y;
+            *sample = y;
+        }
+    }
+}
+
+/// Mutes the signal while its level stays below the threshold.
+pub struct NoiseGate {
+    threshold: f32,
+    level: f32,
+    hold: usize,
+    gain: f32,
+}
+
+impl NoiseGate {
+    pub fn new(threshold_db: f32) -> Self {
+        let threshold = db_to_linear(threshold_db);
+        Self {
+            threshold: threshold * threshold,
+            level: 0.0,
+            hold: 0,
+            gain: 0.0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.level = 0.0;
+        self.hold = 0;
+        self.gain = 0.0;
+    }
+
+    pub fn process(&mut self, samples: &mut [f32]) {
+        for sample in samples {
+            // Mean square level, compared against the squared threshold.
+            self.level += (*sample * *sample - self.level) / GATE_LEVEL_SAMPLES;
+            if self.level >= self.threshold {
+                self.hold = GATE_HOLD_SAMPLES;
+            } else {
+                self.hold = self.hold.saturating_sub(1);
+            }
+
+            let (target, smoothing) = match self.hold {
+                0 => (0.0, GATE_RELEASE_SAMPLES),
+                _ => (1.0, GATE_ATTACK_SAMPLES),
+            };
+            self.gain += (target - self.gain) / smoothing;
+            *sample *= self.gain;
+        }
+    }
+}
+
+/// Spectral subtraction against a slowly adapting noise floor estimate.
+/// Output lags the input by up to one frame, `flush` returns the remainder.
+pub struct NoiseSuppressor {
+    fft: Arc<dyn RealToComplex<f32>>,
+    ifft: Arc<dyn ComplexToReal<f32>>,
+    /// Square root of a periodic Hann window, used for analysis and synthesis.
+    window: Vec<f32>,
+    input: Vec<f32>,
+    overlap: Vec<f32>,
+    frame: Vec<f32>,
+    spectrum: Vec<Complex<f32>>,
+    noise: Vec<f32>,
+    /// Samples taken in but not yet returned.
+    pending: usize,
+}
+
+impl NoiseSuppressor {
+    pub fn new() -> Self {
+        let mut planner = RealFftPlanner::<f32>::new();
+        let fft = planner.plan_fft_forward(FFT_LEN);
+        let ifft = planner.plan_fft_inverse(FFT_LEN);
+        let window = (0..FFT_LEN)
+            .map(|i| (0.5 - 0.5 * (2.0 * PI * i as f32 / FFT_LEN as f32).cos()).sqrt())
+            .collect();
+        let spectrum = fft.make_output_vec();
+        let bins = spectrum.len();
+
+        Self {
+            fft,
+            ifft,
+            window,
+            input: Vec::with_capacity(2 * FFT_LEN),
+            overlap: vec![0.0; FFT_LEN],
+            frame: vec![0.0; FFT_LEN],
+            spectrum,
+            noise: vec![0.0; bins],
+            pending: 0,
+        }
+    }
+
+    /// Forget buffered audio, the noise estimate is kept.
+    pub fn reset(&mut self) {
+        self.input.clear();
+        self.overlap.fill(0.0);
+        self.pending = 0;
+    }
+
+    pub fn process(&mut self, samples: &[f32]) -> Result<Vec<f32>, AudioPipelineError> {
+        self.input.extend_from_slice(samples);
+        self.pending += samples.len();
+
+        let mut out = Vec::with_capacity(samples.len() + HOP_LEN);
+        while self.input.len() >= FFT_LEN {
+            self.process_frame(&mut out)?;
+        }
+        self.pending -= out.len();
+
+        Ok(out)
+    }
+
+    /// Pushes the buffered tail through with zero padding and resets.
+    pub fn flush(&mut self) -> Result<Vec<f32>, AudioPipelineError> {
+        let pending = self.pending;
+        let mut out = Vec::with_capacity(pending + HOP_LEN);
+        while out.len() < pending {
+            self.input.resize(self.input.len() + HOP_LEN, 0.0);
+            while self.input.len() >= FFT_LEN {
+                self.process_frame(&mut out)?;
+            }
+        }
+        out.truncate(pending);
+        self.reset();
+
+        Ok(out)
+    }
+
+    fn process_frame(&mut self, out: &mut Vec<f32>) -> Result<(), AudioPipelineError> {
+        for ((frame, input), window) in self.frame.iter_mut().zip(&self.input).zip(&self.window) {
+            *frame = input * window;
+        }
+        self.fft.process(&mut self.frame, &mut self.spectrum)?;
+
+        for (bin, noise) in self.spectrum.iter_mut().zip(self.noise.iter_mut()) {
+            let power = bin.norm_sqr();
+            // Follow drops in level quickly and rises slowly, so speech barely moves the estimate.
+            *noise = match *noise {
+                0.0 => power,
+                n if power < n => 0.9 * n + 0.1 * power,
+                n => 0.999 * n + 0.001 * power,
+            };
+
+            let gain = match power {
+                p if p > 0.0 => (1.0 - OVER_SUBTRACTION * *noise / p).max(SUPPRESSION_FLOOR * SUPPRESSION_FLOOR).sqrt(),
+                _ => SUPPRESSION_FLOOR,
+            };
+            *bin *= gain;
+        }
+
+        self.ifft.process(&mut self.spectrum, &mut self.frame)?;
+        let scale = 1.0 / FFT_LEN as f32;
+        for ((overlap, frame), window) in self.overlap.iter_mut().zip(&self.frame).zip(&self.window) {
+            *overlap += frame * window * scale;
+        }
+
+        out.extend_from_slice(&self.overlap[..HOP_LEN]);
+        self.overlap.copy_within(HOP_LEN.., 0);
+        self.overlap[FFT_LEN - HOP_LEN..].fill(0.0);
+        self.input.drain(..HOP_LEN);
+
+        Ok(())
+    }
+}
+
+/// The streaming filters enabled in `DspConfig`.
+pub struct Preprocessor {
+    high_pass: Option<HighPass>,
+    noise_suppressor: Option<NoiseSuppressor>,
+    noise_gate: Option<NoiseGate>,
+}
+
+impl Preprocessor {
+    pub fn new(config: &DspConfig) -> Self {
+        Self {
+            high_pass: config.high_pass.then(|| HighPass::new(config.high_pass_cutoff_hz)),
+            noise_suppressor: config.noise_suppression.then(NoiseSuppressor::new),
+            noise_gate: config.noise_gate.then(|| NoiseGate::new(config.noise_gate_threshold_db)),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.high_pass.is_some() || self.noise_suppressor.is_some() || self.noise_gate.is_some()
+    }
+
+    pub fn reset(&mut self) {
+        if let Some(high_pass) = &mut self.high_pass { high_pass.reset(); }
+        if let Some(suppressor) = &mut self.noise_suppressor { suppressor.reset(); }
+        if let Some(gate) = &mut self.noise_gate { gate.reset(); }
+    }
+
+    pub fn process(&mut self, mut samples: Vec<f32>) -> Result<Vec<f32>, AudioPipelineError> {
+        if let Some(high_pass) = &mut self.high_pass {
+            high_pass.process(&mut samples);
+        }
+        if let Some(suppressor) = &mut self.noise_suppressor {
+            samples = suppressor.process(&samples)?;
+        }
+        if let Some(gate) = &mut self.noise_gate {
+            gate.process(&mut samples);
+        }
+
+        Ok(samples)
+    }
+
+    /// Returns audio still held back by the filters, zero padded where needed.
+    pub fn flush(&mut self) -> Result<Vec<f32>, AudioPipelineError> {
+        let mut samples = match &mut self.noise_suppressor {
+            Some(suppressor) => suppressor.flush()?,
+            None => Vec::new(),
+        };
+        if let Some(gate) = &mut self.noise_gate {
+            gate.process(&mut samples);
+        }
+
+        Ok(samples)
+    }
+}
+
+/// Scales `buffer` so that its peak or RMS level matches `target_db`.
+/// The gain is limited so the peak never exceeds full scale.
+pub fn normalize(buffer: &mut [f32], mode: Normalization, target_db: f32) {
+    let peak = buffer.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+    let level = match mode {
+        Normalization::Off => return,
+        Normalization::Peak => peak,
+        Normalization::Rms => rms(buffer),
+    };
+    if level <= f32::EPSILON {
+        return;
+    }
+
+    let gain = (db_to_linear(target_db) / level).min(1.0 / peak);
+    for sample in buffer {
+        *sample *= gain;
+    }
+}
+
+/// Runs the resampled audio through the filters enabled in `[dsp]`.
+/// Audio held back by the filters is flushed before a `Stop` is passed on.
+pub async fn init(
+    mut audio_rx: Receiver<CaptureMsg>,
+) -> Result<(Receiver<CaptureMsg>, JoinHandle<Result<(), AudioPipelineError>>), AudioPipelineError> {
+    let (tx, rx) = mpsc::channel::<CaptureMsg>(10);
+    let handle = tokio::spawn(async move {
+        let mut preprocessor = Preprocessor::new(&CONFIG.dsp);
+        if preprocessor.is_enabled() {
+            debug!("Audio preprocessing enabled: {:?}", CONFIG.dsp);
+        }
+
+        while let Some(msg) = audio_rx.recv().await {
+            match msg {
+                CaptureMsg::Audio(samples) => {
+                    let samples = match preprocessor.process(samples) {
+                        Ok(samples) => samples,
+                        Err(e) => { return Err(e); },
+                    };
+                    if !samples.is_empty() && tx.send(CaptureMsg::Audio(samples)).await.is_err() {
+                        break;
+                    }
+                },
+                CaptureMsg::Stop => {
+                    let tail = match preprocessor.flush() {
+                        Ok(tail) => tail,
+                        Err(e) => { return Err(e); },
+                    };
+                    if !tail.is_empty() && tx.send(CaptureMsg::Audio(tail)).await.is_err() {
+                        break;
+                    }
+                    if tx.send(CaptureMsg::Stop).await.is_err() {
+                        break;
+                    }
+                },
+                CaptureMsg::Start => {
+                    if tx.send(CaptureMsg::Start).await.is_err() {
+                        break;
+                    }
+                },
+                CaptureMsg::Reset | CaptureMsg::Cancel => {
+                    preprocessor.reset();
+                    if tx.send(msg).await.is_err() {
+                        break;
+                    }
+                },
+                CaptureMsg::Reconfigure(_) => { /* Handled by the resampler */ },
+            }
+        }
+
+        Ok(())
+    });
+
+    Ok((rx, handle))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(freq: f32, amplitude: f32, len: usize) -> Vec<f32> {
+        (0..len).map(|i| amplitude * (2.0 * PI * freq * i as f32 / SAMPLE_RATE).sin()).collect()
+    }
+
+    /// RMS of the second half, after the filters settled.
+    fn settled_rms(samples: &[f32]) -> f32 {
+        rms(&samples[samples.len() / 2..])
+    }
+
+    #[test]
+    fn high_pass_attenuates_rumble() {
+        let input = sine(50.0, 0.5, 16_000);
+        let mut output = input.clone();
+        HighPass::new(100.0).process(&mut output);
+
+        assert!(settled_rms(&output) < 0.3 * settled_rms(&input));
+    }
+
+    #[test]
+    fn high_pass_passes_voice() {
+        let input = sine(1_000.0, 0.5, 16_000);
+        let mut output = input.clone();
+        HighPass::new(100.0).process(&mut output);
+
+        assert!(settled_rms(&output) > 0.95 * settled_rms(&input));
+    }
+
+    #[test]
+    fn noise_gate_mutes_signal_below_threshold() {
+        let input = sine(1_000.0, 0.001, 16_000);
+        let mut output = input.clone();
+        NoiseGate::new(-40.0).process(&mut output);
+
+        assert!(settled_rms(&output) < 0.01 * settled_rms(&input));
+    }
+
+    #[test]
+    fn noise_gate_passes_signal_above_threshold() {
+        let input = sine(1_000.0, 0.5, 16_000);
+        let mut output = input.clone();
+        NoiseGate::new(-40.0).process(&mut output);
+
+        assert!(settled_rms(&output) > 0.99 * settled_rms(&input));
+    }
+
+    #[test]
+    fn noise_suppressor_keeps_length() {
+        for len in [1, 255, 256, 257, 511, 512, 1_000, 16_000] {
+            let input = sine(440.0, 0.5, len);
+            let mut suppressor = NoiseSuppressor::new();
+            let mut output = Vec::new();
+            // Uneven chunks, like the ones coming out of the resampler.
+            for chunk in input.chunks(333) {
+                output.extend(suppressor.process(chunk).unwrap());
+            }
+            output.extend(suppressor.flush().unwrap());
+
+            assert_eq!(output.len(), len, "input of {} samples", len);
+        }
+    }
+
+    #[test]
+    fn noise_suppressor_starts_clean_after_flush() {
+        let mut suppressor = NoiseSuppressor::new();
+        suppressor.process(&sine(440.0, 0.5, 1_000)).unwrap();
+        suppressor.flush().unwrap();
+
+        let mut output = suppressor.process(&[0.0; 1_000]).unwrap();
+        output.extend(suppressor.flush().unwrap());
+        assert_eq!(output.len(), 1_000);
+        assert!(output.iter().all(|s| s.abs() < 1e-6));
+    }
+
+    #[test]
+    fn normalize_peak_hits_target() {
+        let mut buffer = sine(440.0, 0.1, 16_000);
+        normalize(&mut buffer, Normalization::Peak, -3.0);
+
+        let peak = buffer.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        assert!((peak - db_to_linear(-3.0)).abs() < 1e-4);
+    }
+
+    #[test]
+    fn normalize_rms_hits_target() {
+        let mut buffer = sine(440.0, 0.1, 16_000);
+        normalize(&mut buffer, Normalization::Rms, -20.0);
+
+        assert!((rms(&buffer) - db_to_linear(-20.0)).abs() < 1e-4);
+    }
+
+    #[test]
+    fn normalize_never_clips() {
+        // Mostly quiet with a single loud click, reaching the RMS target would need a huge gain.
+        let mut buffer = vec![0.001; 16_000];
+        buffer[8_000] = 0.5;
+        normalize(&mut buffer, Normalization::Rms, -3.0);
+
+        let peak = buffer.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        assert!(peak <= 1.0);
+        assert!((peak - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn normalize_leaves_silence_alone() {
+        let mut buffer = vec![0.0; 1_000];
+        normalize(&mut buffer, Normalization::Peak, -3.0);
+
+        assert!(buffer.iter().all(|&s| s == 0.0));
+    }
+}
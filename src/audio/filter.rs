@@ -0,0 +1,154 @@
+use crate::config::FilterConfig;
+
+/// Rejects transcripts that whisper tends to produce for silence or noise,
+/// like "[BLANK_AUDIO]", "(engine revving)" or, if configured, "Thank you.".
+pub struct TranscriptFilter {
+    patterns: Vec<String>,
+}
+
+impl TranscriptFilter {
+    pub fn new(config: &FilterConfig) -> Self {
+        Self {
+            patterns: config.reject_patterns.iter().map(|p| normalize(p)).collect(),
+        }
+    }
+
+    /// Whether the transcript has no words besides bracketed tags,
+    /// or what is left matches one of the reject patterns.
+    pub fn is_junk(&self, text: &str) -> bool {
+        let text = strip_tags(text);
+        if !text.chars().any(char::is_alphanumeric) {
+            return true;
+        }
+
+        let text = normalize(&text);
+        self.patterns.iter().any(|pattern| matches_wildcard(pattern, &text))
+    }
+}
+
+/// Removes tags like "[BLANK_AUDIO]" or "(laughs)", an unclosed bracket is kept as text.
+fn strip_tags(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(['[', '(']) {
+        let close = if rest[start..].starts_with('[') { ']' } else { ')' };
+        match rest[start..].find(close) {
+            Some(len) => {
+                out.push_str(&rest[..start]);
+                out.push(' ');
+                rest = &rest[start + len + 1..];
+            },
+            None => {
+                out.push_str(&rest[..=start]);
+                rest = &rest[start + 1..];
+            },
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Lowercase, single spaced and without surrounding whitespace and sentence punctuation.
+fn normalize(text: &str) -> String {
+    text.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .trim_matches(|c: char| c.is_whitespace() || matches!(c, '.' | ',' | '!' | '?' | '…' | '-'))
+        .to_lowercase()
+}
+
+/// Matches the whole `text` against `pattern`, where `*` stands for any run of characters.
+fn matches_wildcard(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and the text position it was tried at.
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            },
+            Some(&c) if c == text[t] => {
+                p += 1;
+                t += 1;
+            },
+            _ => match backtrack {
+                // Let the last `*` swallow one more character.
+                Some((star, star_t)) => {
+                    backtrack = Some((star, star_t + 1));
+                    p = star + 1;
+                    t = star_t + 1;
+                },
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(reject_patterns: &[&str]) -> TranscriptFilter {
+        TranscriptFilter::new(&FilterConfig {
+            reject_patterns: reject_patterns.iter().map(|p| p.to_string()).collect(),
+            ..FilterConfig::default()
+        })
+    }
+
+    #[test]
+    fn wildcard_positions() {
+        assert!(matches_wildcard("thanks for watching*", "thanks for watching everyone"));
+        assert!(matches_wildcard("*for watching", "thanks for watching"));
+        assert!(matches_wildcard("thanks*watching", "thanks for watching"));
+        assert!(matches_wildcard("*", ""));
+        assert!(matches_wildcard("a*b*c", "abbbc"));
+        // The last `*` has to backtrack past a false start.
+        assert!(matches_wildcard("*ab", "aab"));
+    }
+
+    #[test]
+    fn wildcard_non_matches() {
+        assert!(!matches_wildcard("you", "thank you"));
+        assert!(!matches_wildcard("thank you", "thank you very much"));
+        assert!(!matches_wildcard("*watching", "thanks for watching it"));
+        assert!(!matches_wildcard("a*b*c", "abcb"));
+        assert!(!matches_wildcard("", "a"));
+    }
+
+    #[test]
+    fn only_tags_is_junk() {
+        let filter = filter(&[]);
+        assert!(filter.is_junk("[BLANK_AUDIO]"));
+        assert!(filter.is_junk(" (engine revving) "));
+        assert!(filter.is_junk("(laughs) [music]"));
+        assert!(filter.is_junk("..."));
+        assert!(filter.is_junk(""));
+    }
+
+    #[test]
+    fn tags_around_speech_are_kept() {
+        let filter = filter(&["[*]", "(*)"]);
+        assert!(!filter.is_junk("(laughs) box this lap (laughs)"));
+        assert!(!filter.is_junk("[music] pit now"));
+        assert!(!filter.is_junk("sorry (unclosed"));
+        assert!(filter.is_junk("( [music]"));
+    }
+
+    #[test]
+    fn patterns_ignore_case_punctuation_and_tags() {
+        let filter = filter(&["thank you", "Thanks for watching*"]);
+        assert!(filter.is_junk("Thank you."));
+        assert!(filter.is_junk("  thank you!  "));
+        assert!(filter.is_junk("(applause) Thank you. [music]"));
+        assert!(filter.is_junk("Thanks for watching, see you next time!"));
+        assert!(filter.is_junk("thank  (laughs)  you"));
+        assert!(!filter.is_junk("thank you blue car"));
+        assert!(!filter.is_junk("no thank you"));
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use serde::Deserialize;
+
+use crate::audio::AudioPipelineError;
+
+/// Racing vocabulary loaded from the file at `glossary_path`.
+///
+/// `terms` are passed to the model as context and also fix the casing of
+/// the same words in the transcript. `replace` maps misheard phrases to the
+/// correct spelling, e.g. `"ex are gee" = "XRG"`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Glossary {
+    terms: Vec<String>,
+    replace: HashMap<String, String>,
+}
+
+impl Glossary {
+    pub fn load(path: Option<&str>) -> Result<Self, AudioPipelineError> {
+        let Some(path) = path else { return Ok(Self::default()); };
+
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| AudioPipelineError::Glossary(format!("Failed to read {}: {}", path, e)))?;
+        let glossary: Self = toml::from_str(&contents)
+            .map_err(|e| AudioPipelineError::Glossary(format!("Failed to parse {}: {}", path, e)))?;
+
+        Ok(glossary)
+    }
+
+    /// Builds the prompt that biases the model towards the glossary terms.
+    pub fn initial_prompt(&self, base: &str) -> Option<String> {
+        let mut prompt = base.trim().to_string();
+        if !self.terms.is_empty() {
+            if !prompt.is_empty() {
+                prompt.push(' ');
+            }
+            prompt.push_str(&self.terms.join(", "));
+            prompt.push('.');
+        }
+
+        if prompt.is_empty() { None } else { Some(prompt) }
+    }
+
+    /// Applies the replacement table and term casing to a transcript.
+    pub fn apply(&self, text: &str) -> String {
+        let mut replacements: Vec<(&str, &str)> = self.replace.iter()
+            .map(|(from, to)| (from.as_str(), to.as_str()))
+            .chain(self.terms.iter().map(|term| (term.as_str(), term.as_str())))
+            .filter(|(from, _)| !from.trim().is_empty())
+            .collect();
+        // Longest phrases first, so "pit lane" wins over "pit". Ties are broken by the phrase
+        // itself, the map iterates in a different order on every run.
+        replacements.sort_by(|(a, _), (b, _)| b.chars().count().cmp(&a.chars().count()).then_with(|| a.cmp(b)));
+
+        let mut text = text.to_string();
+        for (from, to) in replacements {
+            text = replace_words(&text, from, to);
+        }
+        text
+    }
+}
+
+/// Case-insensitive replacement of whole-word occurrences of `from`.
+fn replace_words(text: &str, from: &str, to: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let pattern: Vec<char> = from.chars().collect();
+    let is_word_char = |i: usize| chars.get(i).is_some_and(|c| c.is_alphanumeric());
+
+    let mut out = String::with_capacity(text.len());
+    let mut i = 0;
+    while i < chars.len() {
+        let end = i + pattern.len();
+        let matches = end <= chars.len()
+            && !(i > 0 && is_word_char(i - 1))
+            && !is_word_char(end)
+            && chars[i..end].iter().zip(&pattern).all(|(a, b)| a.to_lowercase().eq(b.to_lowercase()));
+
+        if matches {
+            out.push_str(to);
+            i = end;
+        } else {
+            out.push(chars[i]);
+            i += 1;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glossary(toml: &str) -> Glossary {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn replace_words_matches_whole_words_only() {
+        assert_eq!(replace_words("the pit is open", "pit", "PIT"), "the PIT is open");
+        assert_eq!(replace_words("pit", "pit", "PIT"), "PIT");
+        assert_eq!(replace_words("pitlane spit pits", "pit", "PIT"), "pitlane spit pits");
+        assert_eq!(replace_words("pit, pit.", "pit", "PIT"), "PIT, PIT.");
+    }
+
+    #[test]
+    fn replace_words_ignores_case() {
+        assert_eq!(replace_words("Ex Are Gee ahead", "ex are gee", "XRG"), "XRG ahead");
+        assert_eq!(replace_words("ÜBER fast", "über", "Über"), "Über fast");
+    }
+
+    #[test]
+    fn terms_fix_casing() {
+        let glossary = glossary(r#"terms = ["FXO", "Blackwood"]"#);
+        assert_eq!(glossary.apply("fxo at blackwood"), "FXO at Blackwood");
+    }
+
+    #[test]
+    fn longest_phrase_wins() {
+        let glossary = glossary(r#"
+            [replace]
+            "pit" = "box"
+            "pit lane" = "pitlane"
+        "#);
+        assert_eq!(glossary.apply("into the pit lane, then pit"), "into the pitlane, then box");
+    }
+
+    #[test]
+    fn same_length_overlaps_resolve_the_same_every_time() {
+        // Every map gets its own random iteration order.
+        for _ in 0..20 {
+            let glossary = glossary(r#"
+                [replace]
+                "red car" = "A"
+                "car red" = "B"
+            "#);
+            assert_eq!(glossary.apply("red car red"), "red B");
+        }
+    }
+
+    #[test]
+    fn initial_prompt_appends_terms() {
+        let glossary = glossary(r#"terms = ["FXO", "XRG"]"#);
+        assert_eq!(glossary.initial_prompt(""), Some("FXO, XRG.".to_string()));
+        assert_eq!(glossary.initial_prompt(" Race radio. "), Some("Race radio. FXO, XRG.".to_string()));
+        assert_eq!(Glossary::default().initial_prompt("Race radio."), Some("Race radio.".to_string()));
+        assert_eq!(Glossary::default().initial_prompt("  "), None);
+    }
+}
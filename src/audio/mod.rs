@@ -1,10 +1,17 @@
 use tokio::task::JoinError;
 
+mod dsp;
+mod filter;
+mod glossary;
 mod recorder;
 mod resampler;
+mod vad;
 pub mod speech_to_text;
+mod transcriber;
 pub mod audio_pipeline;
 
+pub use recorder::list_input_devices;
+
 #[derive(Debug, thiserror::Error)]
 pub enum AudioPipelineError {
     #[error("audio device error")]
@@ -13,15 +20,30 @@ pub enum AudioPipelineError {
     #[error("resampling error")]
     Resampler(#[from] ResamplerError),
 
+    #[error("audio preprocessing error")]
+    Dsp(#[from] realfft::FftError),
+
     #[error("speech-to-text error")]
     SpeechToText(#[from] whisper_rs::WhisperError),
 
     #[error("model file not found, check you config")]
     ModelNotFound,
 
+    #[error("failed to load glossary: {0}")]
+    Glossary(String),
+
+    #[error("http_backend section is missing from config")]
+    MissingBackendConfig,
+
+    #[error("transcription request failed")]
+    HttpRequest(#[from] ureq::Error),
+
     #[error("audio debug error")]
     AudioDebugError(String),
 
+    #[error("failed to encode WAV audio")]
+    WavEncode(String),
+
     #[error("audio pipeline task error")]
     AudioPipelineTaskJoinError(JoinError)
 }
@@ -34,8 +56,14 @@ pub enum AudioBackendError {
     #[error("no audio input device available")]
     NoInputDevice,
 
-    #[error("unsupported number of input channels. Only mono and stereo input devices are supported.")]
-    UnsupportedInputChannels,
+    #[error("input device \"{0}\" not found, run with --list-devices to see available devices")]
+    InputDeviceNotFound(String),
+
+    #[error("input channel {channel} is not available, the device has {available} channels")]
+    UnsupportedInputChannels { channel: u16, available: usize },
+
+    #[error("unsupported input sample format {0}")]
+    UnsupportedSampleFormat(cpal::SampleFormat),
 
     #[error("failed to play audio stream")]
     PlayStream(#[from] cpal::PlayStreamError),
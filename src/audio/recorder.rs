@@ -1,41 +1,112 @@
-use std::sync::{Arc, atomic::AtomicBool};
+use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
 
-use cpal::{SampleRate, Stream, traits::{DeviceTrait, HostTrait, StreamTrait}};
-use tokio::{sync::mpsc::{self, Receiver}};
-use tracing::{error, info, warn};
+use cpal::{Device, FromSample, Host, Sample, SampleFormat, SampleRate, SizedSample, Stream, StreamConfig, SupportedStreamConfig, traits::{DeviceTrait, HostTrait}};
+use tokio::sync::mpsc::{Sender, UnboundedSender};
+use tracing::{debug, error, info, warn};
 
-use crate::audio::{AudioBackendError, audio_pipeline::CaptureMsg};
+use crate::{audio::{AudioBackendError, audio_pipeline::{CaptureMsg, PipelineEvent}}, global::CONFIG};
 
+#[derive(Debug, Clone, Copy)]
 pub struct AudioInputConfig {
-    pub input_channels: usize,
     pub sample_rate: SampleRate,
 }
 
-pub fn init(
+/// Everything the stream callbacks need to talk to the rest of the pipeline.
+struct StreamContext {
     is_recording: Arc<AtomicBool>,
-) -> Result<(Stream, AudioInputConfig, Receiver<CaptureMsg>), AudioBackendError> {
-    let (audio_tx, audio_rx) = mpsc::channel::<CaptureMsg>(10);
+    is_listening: Arc<AtomicBool>,
+    audio_tx: Sender<CaptureMsg>,
+    device_lost_tx: UnboundedSender<PipelineEvent>,
+    stream_id: u32,
+}
 
-    let host = cpal::default_host();
-    let device = match host.default_input_device() {
-        Some(device) => device,
-        None => return Err(AudioBackendError::NoInputDevice),
-    };
-    let input_config = device.default_input_config()?;
-    let input_channels = input_config.channels() as usize;
-    if (input_channels != 1) && (input_channels != 2) {
-        return Err(AudioBackendError::UnsupportedInputChannels);
+/// The input device picked in the config and the format its stream is going to have.
+pub struct InputDevice {
+    device: Device,
+    config: SupportedStreamConfig,
+}
+
+impl InputDevice {
+    pub fn open() -> Result<Self, AudioBackendError> {
+        let host = cpal::default_host();
+        let device = find_input_device(&host)?;
+        let config = device.default_input_config()?;
+
+        Ok(Self { device, config })
+    }
+
+    pub fn config(&self) -> AudioInputConfig {
+        AudioInputConfig {
+            sample_rate: self.config.sample_rate(),
+        }
     }
+}
+
+/// Builds the input stream, it streams mono audio into `audio_tx` once played.
+/// Losing the device or any other fatal stream error is reported as
+/// `PipelineEvent::DeviceLost(stream_id)` on `device_lost_tx`.
+pub fn init(
+    input: InputDevice,
+    is_recording: Arc<AtomicBool>,
+    is_listening: Arc<AtomicBool>,
+    audio_tx: Sender<CaptureMsg>,
+    device_lost_tx: UnboundedSender<PipelineEvent>,
+    stream_id: u32,
+) -> Result<Stream, AudioBackendError> {
+    let InputDevice { device, config: input_config } = input;
+    let input_channels = input_config.channels() as usize;
+    let selected_channels = select_channels(input_channels, CONFIG.input_channels.as_deref())?;
 
     let sample_rate = input_config.sample_rate();
-    let audio_tx_clone = audio_tx.clone();
+    let sample_format = input_config.sample_format();
+    let stream_config: StreamConfig = input_config.into();
+    let context = StreamContext { is_recording, is_listening, audio_tx, device_lost_tx, stream_id };
+    let stream = match sample_format {
+        SampleFormat::F32 => build_stream::<f32>(&device, &stream_config, selected_channels, context)?,
+        SampleFormat::F64 => build_stream::<f64>(&device, &stream_config, selected_channels, context)?,
+        SampleFormat::I8 => build_stream::<i8>(&device, &stream_config, selected_channels, context)?,
+        SampleFormat::I16 => build_stream::<i16>(&device, &stream_config, selected_channels, context)?,
+        SampleFormat::I24 => build_stream::<cpal::I24>(&device, &stream_config, selected_channels, context)?,
+        SampleFormat::I32 => build_stream::<i32>(&device, &stream_config, selected_channels, context)?,
+        SampleFormat::U8 => build_stream::<u8>(&device, &stream_config, selected_channels, context)?,
+        SampleFormat::U16 => build_stream::<u16>(&device, &stream_config, selected_channels, context)?,
+        SampleFormat::U32 => build_stream::<u32>(&device, &stream_config, selected_channels, context)?,
+        other => return Err(AudioBackendError::UnsupportedSampleFormat(other)),
+    };
+
+    info!(
+        "Using input device: {} ({} ch, {} Hz, {})",
+        device_name(&device), input_channels, sample_rate, sample_format,
+    );
+
+    Ok(stream)
+}
+
+fn build_stream<T>(
+    device: &Device,
+    stream_config: &StreamConfig,
+    selected_channels: Vec<usize>,
+    context: StreamContext,
+) -> Result<Stream, AudioBackendError>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let input_channels = stream_config.channels as usize;
+    let StreamContext { is_recording, is_listening, audio_tx, device_lost_tx, stream_id } = context;
+    // The capture task needs audio from before the talk bind for its pre-roll.
+    let always_forward = CONFIG.pre_roll_ms > 0;
     let stream = device.build_input_stream(
-        &input_config.into(),
-        move |data: &[f32], _| {
-            if is_recording.load(std::sync::atomic::Ordering::Relaxed) {
-                match audio_tx.try_send(CaptureMsg::Audio(data.to_vec())) {
+        stream_config,
+        move |data: &[T], _| {
+            let recording = is_recording.load(Ordering::Relaxed);
+            if always_forward || recording || is_listening.load(Ordering::Relaxed) {
+                let mono = downmix(data, input_channels, &selected_channels);
+                match audio_tx.try_send(CaptureMsg::Audio(mono)) {
                     Ok(_) => (),
-                    Err(e) => error!("Failed to send audio data: {}", e),
+                    Err(e) if recording => error!("Failed to send audio data: {}", e),
+                    // Only pre-roll or hands-free monitoring is lost.
+                    Err(e) => debug!("Failed to send idle audio data: {}", e),
                 };
             }
         },
@@ -43,32 +114,149 @@ pub fn init(
             match err {
                 cpal::StreamError::DeviceNotAvailable => error!("Audio input device not available"),
                 cpal::StreamError::StreamInvalidated => error!("Audio stream invalidated"),
-                cpal::StreamError::BufferUnderrun => error!("Audio buffer underrun"),
+                // Some hosts report an unplugged device like this, reopening the stream covers both.
                 cpal::StreamError::BackendSpecific { err } => error!("Audio stream error: {}", err),
+                cpal::StreamError::BufferUnderrun => {
+                    // A few samples are lost, the stream keeps running.
+                    warn!("Audio buffer underrun");
+                    return;
+                },
             }
-            let _ = audio_tx_clone.blocking_send(CaptureMsg::Exit);
+            let _ = device_lost_tx.send(PipelineEvent::DeviceLost(stream_id));
         },
         None,
     )?;
 
-    match stream.play() {
-        Ok(()) => (),
-        Err(e) => error!("Failed to start audio stream: {}", e),
+    Ok(stream)
+}
+
+/// Resolves the one based `input_channels` from the config into zero based channel indices.
+/// All channels are averaged when nothing is configured.
+fn select_channels(input_channels: usize, selected: Option<&[u16]>) -> Result<Vec<usize>, AudioBackendError> {
+    let Some(selected) = selected else {
+        return Ok((0..input_channels).collect());
+    };
+
+    selected.iter()
+        .map(|&channel| match channel as usize {
+            n if (1..=input_channels).contains(&n) => Ok(n - 1),
+            _ => Err(AudioBackendError::UnsupportedInputChannels { channel, available: input_channels }),
+        })
+        .collect()
+}
+
+/// Converts interleaved frames into f32 mono by averaging the selected channels.
+fn downmix<T>(data: &[T], input_channels: usize, selected_channels: &[usize]) -> Vec<f32>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let gain = 1.0 / selected_channels.len() as f32;
+    data.chunks_exact(input_channels)
+        .map(|frame| {
+            selected_channels.iter()
+                .map(|&channel| frame[channel].to_sample::<f32>())
+                .sum::<f32>() * gain
+        })
+        .collect()
+}
+
+/// Prints every input device with the stream configs it supports.
+pub fn list_input_devices() -> Result<(), AudioBackendError> {
+    let host = cpal::default_host();
+    let default_name = host.default_input_device().map(|device| device_name(&device));
+
+    for device in host.input_devices()? {
+        let name = device_name(&device);
+        let default = if default_name.as_ref() == Some(&name) { " (default)" } else { "" };
+        println!("{}{}", name, default);
+
+        match device.supported_input_configs() {
+            Ok(configs) => {
+                for config in configs {
+                    println!(
+                        "    {} ch, {}-{} Hz, {}",
+                        config.channels(),
+                        config.min_sample_rate(),
+                        config.max_sample_rate(),
+                        config.sample_format(),
+                    );
+                }
+            },
+            Err(e) => println!("    Failed to get supported configs: {}", e),
+        }
     }
 
-    let name = match device.description() {
+    Ok(())
+}
+
+/// Picks the device configured in `input_device`, or the system default.
+fn find_input_device(host: &Host) -> Result<Device, AudioBackendError> {
+    let Some(wanted) = &CONFIG.input_device else {
+        return host.default_input_device().ok_or(AudioBackendError::NoInputDevice);
+    };
+
+    let wanted_lower = wanted.to_lowercase();
+    host.input_devices()?
+        .find(|device| device_name(device).to_lowercase().contains(&wanted_lower))
+        .ok_or_else(|| AudioBackendError::InputDeviceNotFound(wanted.clone()))
+}
+
+fn device_name(device: &Device) -> String {
+    match device.description() {
         Ok(desc) => desc.to_string(),
         Err(_) => {
             warn!("Failed to get audio input device name");
             "Unknown Device".to_string()
         }
-    };
-    info!("Using input device: {}", name);
+    }
+}
 
-    let config = AudioInputConfig {
-        input_channels,
-        sample_rate,
-    };
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_samples(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len(), "{:?} != {:?}", actual, expected);
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-6, "{:?} != {:?}", actual, expected);
+        }
+    }
 
-    Ok((stream, config, audio_rx))
+    #[test]
+    fn integer_samples_are_scaled_to_unit_range() {
+        assert_samples(&downmix(&[i16::MIN, 0, 16_384], 1, &[0]), &[-1.0, 0.0, 0.5]);
+        assert_samples(&downmix(&[i32::MIN, 0, 1 << 30], 1, &[0]), &[-1.0, 0.0, 0.5]);
+    }
+
+    #[test]
+    fn single_channel_is_picked_from_each_frame() {
+        let data = [0.1, 0.2, 0.3, 0.4, 0.5, 0.6];
+        assert_samples(&downmix(&data, 3, &[1]), &[0.2, 0.5]);
+        assert_samples(&downmix(&data, 3, &[2]), &[0.3, 0.6]);
+    }
+
+    #[test]
+    fn selected_channels_are_averaged() {
+        let data = [0.1, 0.9, 0.3, 0.4, 0.9, 0.6];
+        assert_samples(&downmix(&data, 3, &[0, 2]), &[0.2, 0.5]);
+        assert_samples(&downmix(&[0.5f32, -0.5], 2, &[0, 1]), &[0.0]);
+    }
+
+    #[test]
+    fn trailing_partial_frame_is_dropped() {
+        assert_samples(&downmix(&[0.1f32, 0.2, 0.3, 0.4, 0.5], 2, &[0]), &[0.1, 0.3]);
+    }
+
+    #[test]
+    fn channels_are_resolved_from_config() {
+        assert_eq!(select_channels(2, None).unwrap(), vec![0, 1]);
+        assert_eq!(select_channels(4, Some(&[2][..])).unwrap(), vec![1]);
+        assert_eq!(select_channels(4, Some(&[1, 4][..])).unwrap(), vec![0, 3]);
+        assert!(matches!(
+            select_channels(2, Some(&[3][..])),
+            Err(AudioBackendError::UnsupportedInputChannels { channel: 3, available: 2 })
+        ));
+        assert!(select_channels(2, Some(&[0][..])).is_err());
+    }
 }
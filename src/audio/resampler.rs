@@ -1,87 +1,302 @@
 use rubato::{
-    Async, FixedAsync,
+    Async, FixedAsync, PolynomialDegree,
     SincInterpolationParameters, SincInterpolationType, WindowFunction,
     Resampler,
 };
-use whisper_rs::convert_stereo_to_mono_audio;
-use tokio::{sync::mpsc::{Sender, Receiver}, task::JoinHandle};
+use tokio::{sync::mpsc::Receiver, task::JoinHandle};
+use tracing::info;
 
-use crate::audio::{AudioPipelineError, audio_pipeline::CaptureMsg};
+use crate::{audio::{AudioPipelineError, audio_pipeline::CaptureMsg}, config::ResamplerQuality, global::CONFIG};
 
-pub async fn init(
-    mut audio_rx: Receiver<CaptureMsg>,
-    sample_rate: usize,
-    input_channels: usize,
-) -> Result<(Sender<CaptureMsg>, Receiver<CaptureMsg>, JoinHandle<Result<(), AudioPipelineError>>), AudioPipelineError> {
-    let (resampled_tx, resampled_rx) = tokio::sync::mpsc::channel::<CaptureMsg>(10);
-    let resampled_tx_clone = resampled_tx.clone();
-    let handle = tokio::spawn(async move {
-        let mut input_accum: Vec<f32> = Vec::new();
+const CHUNK_SIZE: usize = 1024;
 
-        let sinc_params = SincInterpolationParameters {
+fn build_resampler(sample_rate: usize, quality: ResamplerQuality) -> Result<Async<f32>, AudioPipelineError> {
+    let ratio = 16_000.0 / sample_rate as f64;
+    let sinc_params = match quality {
+        ResamplerQuality::Fast => {
+            return match Async::<f32>::new_poly(
+                ratio,
+                1.0, // no dynamic ratio range
+                PolynomialDegree::Cubic,
+                CHUNK_SIZE,
+                1, // nbr_channels
+                FixedAsync::Input,
+            ) {
+                Ok(r) => Ok(r),
+                Err(e) => Err(AudioPipelineError::Resampler(e.into())),
+            };
+        },
+        ResamplerQuality::Balanced => SincInterpolationParameters {
             sinc_len: 128,
             f_cutoff: 0.95,
             interpolation: SincInterpolationType::Linear,
             oversampling_factor: 256,
             window: WindowFunction::BlackmanHarris2,
+        },
+        ResamplerQuality::HighQuality => SincInterpolationParameters {
+            sinc_len: 256,
+            f_cutoff: 0.95,
+            interpolation: SincInterpolationType::Cubic,
+            oversampling_factor: 256,
+            window: WindowFunction::BlackmanHarris2,
+        },
+    };
+
+    match Async::<f32>::new_sinc(
+        ratio,
+        1.0, // no dynamic ratio range
+        &sinc_params,
+        CHUNK_SIZE,
+        1, // nbr_channels
+        FixedAsync::Input,
+    ) {
+        Ok(r) => Ok(r),
+        Err(e) => Err(AudioPipelineError::Resampler(e.into())),
+    }
+}
+
+/// Resamples a continuous mono stream to 16 kHz in fixed size chunks.
+/// Audio that is already at 16 kHz is passed through untouched.
+pub struct StreamResampler {
+    /// None when the input is already at 16 kHz.
+    resampler: Option<Async<f32>>,
+    ratio: f64,
+    input: Vec<f32>,
+    output: Vec<f32>,
+    /// Frames taken in and given out since the last reset.
+    frames_in: usize,
+    frames_out: usize,
+}
+
+impl StreamResampler {
+    pub fn new(sample_rate: usize, quality: ResamplerQuality) -> Result<Self, AudioPipelineError> {
+        let resampler = match sample_rate {
+            16_000 => None,
+            _ => Some(build_resampler(sample_rate, quality)?),
+        };
+        let output = match &resampler {
+            Some(resampler) => vec![0.0; resampler.output_frames_max()],
+            None => Vec::new(),
+        };
+        match &resampler {
+            Some(_) => info!("Resampling {} Hz input to 16 kHz ({:?})", sample_rate, quality),
+            None => info!("Input already at 16 kHz, resampling bypassed"),
+        }
+
+        Ok(Self {
+            resampler,
+            ratio: 16_000.0 / sample_rate as f64,
+            input: Vec::with_capacity(2 * CHUNK_SIZE),
+            output,
+            frames_in: 0,
+            frames_out: 0,
+        })
+    }
+
+    /// Resamples every full chunk available, the rest is kept for the next call.
+    pub fn process(&mut self, samples: &[f32]) -> Result<Vec<f32>, AudioPipelineError> {
+        if self.resampler.is_none() {
+            return Ok(samples.to_vec());
+        }
+
+        self.input.extend_from_slice(samples);
+        self.frames_in += samples.len();
+
+        let mut out = Vec::new();
+        while self.input.len() >= CHUNK_SIZE {
+            self.process_chunk(&mut out)?;
+        }
+        Ok(out)
+    }
+
+    /// Zero pads the remaining input and returns everything the resampler still
+    /// holds back, so the output covers all input since the last reset. Resets afterwards.
+    pub fn flush(&mut self) -> Result<Vec<f32>, AudioPipelineError> {
+        let delay = match &self.resampler {
+            Some(resampler) if self.frames_in > 0 => resampler.output_delay(),
+            _ => {
+                self.reset();
+                return Ok(Vec::new());
+            },
         };
 
-        let ratio = 16_000.0 / sample_rate as f64;
-        let chunk_size = 1024;
-        let mut resampler = match Async::<f32>::new_sinc(
-            ratio,
-            1.0, // no dynamic ratio range
-            &sinc_params,
-            chunk_size,
-            1, // nbr_channels
-            FixedAsync::Input,
-        ) {
+        let expected = delay + (self.frames_in as f64 * self.ratio).round() as usize;
+        let mut out = Vec::new();
+        while self.frames_out < expected {
+            self.input.resize(CHUNK_SIZE, 0.0);
+            self.process_chunk(&mut out)?;
+        }
+        // Drop the output of the padding.
+        let excess = self.frames_out.saturating_sub(expected).min(out.len());
+        out.truncate(out.len() - excess);
+        self.reset();
+
+        Ok(out)
+    }
+
+    pub fn reset(&mut self) {
+        if let Some(resampler) = &mut self.resampler {
+            resampler.reset();
+        }
+        self.input.clear();
+        self.frames_in = 0;
+        self.frames_out = 0;
+    }
+
+    fn process_chunk(&mut self, out: &mut Vec<f32>) -> Result<(), AudioPipelineError> {
+        let Some(resampler) = &mut self.resampler else { return Ok(()); };
+        let chunk: Vec<f32> = self.input.drain(..CHUNK_SIZE).collect();
+        let output_frames_max = self.output.len();
+
+        let (_, out_frames) = match resampler
+            .process_into_buffer(
+                &audioadapter_buffers::direct::InterleavedSlice::new(&chunk, 1, chunk.len()).unwrap(),
+                &mut audioadapter_buffers::direct::InterleavedSlice::new_mut(&mut self.output, 1, output_frames_max).unwrap(),
+                None,
+            ) {
+                Ok(r) => r,
+                Err(e) => { return Err(AudioPipelineError::Resampler(e.into())); }
+            };
+
+        out.extend_from_slice(&self.output[..out_frames]);
+        self.frames_out += out_frames;
+        Ok(())
+    }
+}
+
+/// Resamples the recorder output to 16 kHz. On `Stop` the buffered tail is
+/// flushed before `Stop` is passed on, `Stop`, `Reset` and `Cancel` all reset
+/// the resampler so nothing leaks into the next recording.
+pub async fn init(
+    mut audio_rx: Receiver<CaptureMsg>,
+    sample_rate: usize,
+) -> Result<(Receiver<CaptureMsg>, JoinHandle<Result<(), AudioPipelineError>>), AudioPipelineError> {
+    let (resampled_tx, resampled_rx) = tokio::sync::mpsc::channel::<CaptureMsg>(10);
+    let handle = tokio::spawn(async move {
+        let mut resampler = match StreamResampler::new(sample_rate, CONFIG.resampler_quality) {
             Ok(r) => r,
-            Err(e) => { return Err(AudioPipelineError::Resampler(e.into())); }
+            Err(e) => { return Err(e); }
         };
 
         loop {
-            let samples = match audio_rx.recv().await {
-                Some(msg) => match msg {
-                    CaptureMsg::Audio(samples) => { samples },
-                    CaptureMsg::Stop => { continue; },
-                    CaptureMsg::Exit => { return Ok(()) }, // exit signal, stop resampling task
-                },
+            let msg = match audio_rx.recv().await {
+                Some(msg) => msg,
                 None => { return Ok(()); },
             };
 
-            let mono = match input_channels {
-                1 => samples,
-                2 => convert_stereo_to_mono_audio(&samples).expect("should be no half samples missing"),
-                _ => panic!("Unsupported number of input channels: {}", input_channels),
-            };
-
-            input_accum.extend_from_slice(&mono);
-            if input_accum.len() < 1024 {
-                continue;
+            match msg {
+                CaptureMsg::Audio(samples) => {
+                    let out = match resampler.process(&samples) {
+                        Ok(out) => out,
+                        Err(e) => { return Err(e); }
+                    };
+                    if !out.is_empty() {
+                        let _ = resampled_tx.send(CaptureMsg::Audio(out)).await;
+                    }
+                },
+                CaptureMsg::Stop => {
+                    let tail = match resampler.flush() {
+                        Ok(tail) => tail,
+                        Err(e) => { return Err(e); }
+                    };
+                    if !tail.is_empty() {
+                        let _ = resampled_tx.send(CaptureMsg::Audio(tail)).await;
+                    }
+                    let _ = resampled_tx.send(CaptureMsg::Stop).await;
+                },
+                CaptureMsg::Start => {
+                    let _ = resampled_tx.send(CaptureMsg::Start).await;
+                },
+                CaptureMsg::Reset | CaptureMsg::Cancel => {
+                    resampler.reset();
+                    let _ = resampled_tx.send(msg).await;
+                },
+                CaptureMsg::Reconfigure(config) => {
+                    // The stream was rebuilt, possibly on a different device.
+                    resampler = match StreamResampler::new(config.sample_rate as usize, CONFIG.resampler_quality) {
+                        Ok(r) => r,
+                        Err(e) => { return Err(e); }
+                    };
+                },
             }
+        }
+    });
 
-            let mono: Vec<f32> = input_accum.drain(..1024).collect();
+    Ok((resampled_rx, handle))
+}
 
-            // prep output adapters (same shape, but resized to max)
-            let mut out = vec![0.0; resampler.output_frames_max()];
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            // process into buffer
-            let (_, out_frames) = match resampler
-                .process_into_buffer(
-                    &audioadapter_buffers::direct::InterleavedSlice::new(&mono, 1, mono.len()).unwrap(),
-                    &mut audioadapter_buffers::direct::InterleavedSlice::new_mut(&mut out, 1, resampler.output_frames_max()).unwrap(),
-                    None,
-                ) {
-                    Ok(r) => r,
-                    Err(e) => { return Err(AudioPipelineError::Resampler(e.into())); }
-                };
+    const SAMPLE_RATES: [usize; 2] = [44_100, 48_000];
+    const QUALITIES: [ResamplerQuality; 3] = [
+        ResamplerQuality::Fast,
+        ResamplerQuality::Balanced,
+        ResamplerQuality::HighQuality,
+    ];
 
-            out.truncate(out_frames);
-            let _ = resampled_tx_clone.send(CaptureMsg::Audio(out)).await;
+    /// Feeds `input` in blocks like a sound card would deliver them, then flushes.
+    fn resample_recording(resampler: &mut StreamResampler, input: &[f32]) -> Vec<f32> {
+        let mut output = Vec::new();
+        for block in input.chunks(441) {
+            output.extend(resampler.process(block).unwrap());
         }
-    });
+        output.extend(resampler.flush().unwrap());
+        output
+    }
 
-    Ok((resampled_tx, resampled_rx, handle))
-}
+    fn expected_len(resampler: &StreamResampler, frames: usize, sample_rate: usize) -> usize {
+        let delay = resampler.resampler.as_ref().map_or(0, |r| r.output_delay());
+        delay + (frames as f64 * 16_000.0 / sample_rate as f64).round() as usize
+    }
+
+    #[test]
+    fn flush_returns_every_sample() {
+        for sample_rate in SAMPLE_RATES {
+            for quality in QUALITIES {
+                for frames in [1, 1023, 1025, 48_000] {
+                    let mut resampler = StreamResampler::new(sample_rate, quality).unwrap();
+                    let expected = expected_len(&resampler, frames, sample_rate);
+                    let output = resample_recording(&mut resampler, &vec![0.5; frames]);
+
+                    assert_eq!(output.len(), expected, "{} frames at {} Hz ({:?})", frames, sample_rate, quality);
+                    if frames == 48_000 {
+                        assert!((output[expected / 2] - 0.5).abs() < 0.02);
+                    }
+                }
+            }
+        }
+    }
 
+    #[test]
+    fn input_at_16k_is_passed_through() {
+        let input: Vec<f32> = (0..1025).map(|i| i as f32 / 1025.0).collect();
+        let mut resampler = StreamResampler::new(16_000, ResamplerQuality::Balanced).unwrap();
+
+        assert_eq!(resample_recording(&mut resampler, &input), input);
+    }
+
+    #[test]
+    fn next_recording_has_no_leftovers() {
+        for sample_rate in SAMPLE_RATES {
+            for quality in QUALITIES {
+                let mut resampler = StreamResampler::new(sample_rate, quality).unwrap();
+                let expected = expected_len(&resampler, 1025, sample_rate);
+
+                // Cancelled in the middle of a chunk.
+                resampler.process(&[0.5; 1500]).unwrap();
+                resampler.reset();
+                let output = resample_recording(&mut resampler, &[0.0; 1025]);
+                assert_eq!(output.len(), expected);
+                assert!(output.iter().all(|s| s.abs() < 1e-6), "leftovers after reset at {} Hz ({:?})", sample_rate, quality);
+
+                // Stopped and flushed.
+                resample_recording(&mut resampler, &[0.5; 1500]);
+                let output = resample_recording(&mut resampler, &[0.0; 1025]);
+                assert_eq!(output.len(), expected);
+                assert!(output.iter().all(|s| s.abs() < 1e-6), "leftovers after flush at {} Hz ({:?})", sample_rate, quality);
+            }
+        }
+    }
+}
@@ -1,91 +1,179 @@
-use std::fmt::Display;
+use std::{fmt::Display, sync::{Arc, atomic::AtomicU64}};
 use tokio::{sync::mpsc::{self, Receiver}, task::JoinHandle};
-use tracing::info;
-use whisper_rs::{FullParams, WhisperContext, WhisperContextParameters, install_logging_hooks};
-use crate::{audio::{AudioPipelineError}, global::CONFIG};
+use tracing::{debug, info};
+use crate::{audio::{AudioPipelineError, filter::TranscriptFilter, glossary::Glossary, transcriber::{self, CancelToken, TranscribeOptions, Transcriber}}, global::CONFIG};
 
 pub enum SttMessageType {
     TranscriptionError,
     TranscriptionResult,
+    /// The recording contained nothing to transcribe.
+    NoSpeech,
+    /// Transcript of the audio recorded so far, replaced by the final result once recording ends.
+    PartialResult,
+}
+
+/// Audio sent to the STT task.
+pub struct SttRequest {
+    pub audio: Vec<f32>,
+    /// Still recording, the buffer will grow.
+    pub partial: bool,
+    /// Pipeline generation the audio was recorded in.
+    pub generation: u64,
+    /// Settings of the channel the recording was made for.
+    pub options: TranscribeOptions,
+}
+
+impl SttRequest {
+    pub fn new(audio: Vec<f32>, partial: bool, generation: u64, options: TranscribeOptions) -> Self {
+        Self { audio, partial, generation, options }
+    }
 }
 
 pub struct SttMessage {
     pub msg_type: SttMessageType,
     pub content: String,
+    /// Language the backend transcribed in, if it reports one.
+    pub language: Option<String>,
+    /// Generation of the request this message answers.
+    pub generation: u64,
 }
 
 impl Display for SttMessage {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self.msg_type {
             SttMessageType::TranscriptionError => write!(f, "[STT ERROR] {}", self.content),
-            SttMessageType::TranscriptionResult => write!(f, "[STT TRANSCRIPTION] {}", self.content),
+            SttMessageType::TranscriptionResult => match &self.language {
+                Some(language) => write!(f, "[STT TRANSCRIPTION] ({}) {}", language, self.content),
+                None => write!(f, "[STT TRANSCRIPTION] {}", self.content),
+            },
+            SttMessageType::NoSpeech => write!(f, "[STT] No speech detected"),
+            SttMessageType::PartialResult => write!(f, "[STT PARTIAL] {}", self.content),
         }
     }
 }
 
 impl SttMessage {
     pub fn new(msg_type: SttMessageType, content: String) -> Self {
-        Self { msg_type, content }
+        Self { msg_type, content, language: None, generation: 0 }
+    }
+
+    pub fn with_language(mut self, language: Option<String>) -> Self {
+        self.language = language;
+        self
+    }
+
+    pub fn with_generation(mut self, generation: u64) -> Self {
+        self.generation = generation;
+        self
+    }
+}
+
+/// Turns recorded audio into the message for the UI, with junk dropped and the glossary applied.
+struct SttWorker {
+    transcriber: Box<dyn Transcriber>,
+    glossary: Glossary,
+    filter: TranscriptFilter,
+}
+
+impl SttWorker {
+    /// Returns None if there is nothing to report, e.g. a failed partial.
+    fn process(&mut self, request: &SttRequest, cancel: &CancelToken) -> Option<SttMessage> {
+        if request.audio.is_empty() {
+            // Nothing but silence was recorded.
+            return match request.partial {
+                true => None,
+                false => Some(SttMessage::new(SttMessageType::NoSpeech, String::new()).with_generation(request.generation)),
+            };
+        }
+
+        let mut msg = self.transcriber.transcribe(&request.audio, &request.options, cancel)
+            .with_generation(request.generation);
+        match (&msg.msg_type, request.partial) {
+            (SttMessageType::TranscriptionError, true) => {
+                // The final transcription will report the error if it persists.
+                debug!("Partial {}", msg);
+                return None;
+            },
+            (SttMessageType::TranscriptionResult, true) => {
+                msg.msg_type = SttMessageType::PartialResult;
+            },
+            _ => (),
+        }
+        if !matches!(msg.msg_type, SttMessageType::TranscriptionError) {
+            if !msg.content.is_empty() && self.filter.is_junk(&msg.content) {
+                debug!("Dropping transcript without speech: {}", msg.content);
+                msg.content.clear();
+            }
+            msg.content = self.glossary.apply(&msg.content);
+        }
+        if matches!(msg.msg_type, SttMessageType::TranscriptionResult) && msg.content.is_empty() {
+            msg.msg_type = SttMessageType::NoSpeech;
+        }
+
+        Some(msg)
     }
 }
 
 pub async fn init(
-    mut audio_in: Receiver<Vec<f32>>
+    mut audio_in: Receiver<SttRequest>,
+    mut partials_in: Receiver<SttRequest>,
+    generation: Arc<AtomicU64>,
 ) -> Result<(Receiver<SttMessage>, JoinHandle<Result<(), AudioPipelineError>>), AudioPipelineError> {
     let (event_tx, event_rx) = mpsc::channel::<SttMessage>(1);
 
     let handle = tokio::spawn(async move {
-        install_logging_hooks();
-        let mut params = WhisperContextParameters::new();
-        params.use_gpu(CONFIG.use_gpu);
-        // check if model path exists:
-        if !std::path::Path::new(&CONFIG.model_path).exists() {
-            return Err(AudioPipelineError::ModelNotFound);
-        }
-        let whisper_ctx = WhisperContext::new_with_params(CONFIG.model_path.as_str(), params)?;
-        let mut whisper_state = match whisper_ctx.create_state() {
-            Ok(state) => state,
-            Err(err) => {return Err(err.into());}
+        let glossary = match Glossary::load(CONFIG.glossary_path.as_deref()) {
+            Ok(glossary) => glossary,
+            Err(err) => { return Err(err); }
+        };
+        let prompt = glossary.initial_prompt(&CONFIG.whisper.initial_prompt);
+        let transcriber = match transcriber::from_config(prompt) {
+            Ok(transcriber) => transcriber,
+            Err(err) => { return Err(err); }
+        };
+
+        let mut worker = SttWorker {
+            transcriber,
+            glossary,
+            filter: TranscriptFilter::new(&CONFIG.filter),
         };
-        let mut full_params = FullParams::new(whisper_rs::SamplingStrategy::Greedy { best_of: 8 });
-        full_params.set_language(Some("en"));
-        full_params.set_print_special(false);
-        full_params.set_print_progress(false);
-        full_params.set_print_realtime(false);
-        full_params.set_print_timestamps(false);
 
         info!("✅ STT thread started");
 
         loop {
-            while let Some(audio_buffer) = audio_in.recv().await {
-                match maybe_dump_buffer_to_wav(&audio_buffer) {
+            let request = tokio::select! {
+                // Finals first, so a stale partial never delays the result of a finished recording.
+                biased;
+                Some(request) = audio_in.recv() => {
+                    // Whatever partial is still waiting is about audio the final already covers.
+                    while partials_in.try_recv().is_ok() {}
+                    request
+                },
+                Some(request) = partials_in.recv() => request,
+                else => { return Ok(()); },
+            };
+
+            let cancel = CancelToken::new(generation.clone(), request.generation);
+            if cancel.is_cancelled() {
+                debug!("Skipping transcription of discarded recording");
+                continue;
+            }
+
+            if !request.partial {
+                match maybe_dump_buffer_to_wav(&request.audio) {
                     Ok(_) => (),
                     Err(err) => { return Err(err); }
                 };
-                if let Err(err) = whisper_state.full(full_params.clone(), &audio_buffer) {
-                    let _ = event_tx.send(
-                        SttMessage::new(
-                            SttMessageType::TranscriptionError,
-                            format!("❌ Transcription error: {:?}", err)
-                        )
-                    ).await;
-                    continue;
-                }
-
-                let mut text = String::new();
-                let n_segments = whisper_state.full_n_segments();
-                for i in 0..n_segments {
-                    if let Some(segment) = whisper_state.get_segment(i) && let Ok(segment) = segment.to_str() {
-                        text.push_str(segment);
-                    }
-                }
-
-                let _ = event_tx.send(
-                    SttMessage::new(
-                        SttMessageType::TranscriptionResult,
-                        text.trim().to_string()
-                    )
-                ).await;
+            }
+
+            // Backends block until the transcription is done, keep that off the async workers.
+            let msg = tokio::task::block_in_place(|| worker.process(&request, &cancel));
+            if cancel.is_cancelled() {
+                debug!("Dropping transcription of discarded recording");
+                continue;
+            }
+            if let Some(msg) = msg {
+                let _ = event_tx.send(msg).await;
             }
         }
     });
@@ -96,20 +184,96 @@ pub async fn init(
 fn maybe_dump_buffer_to_wav(samples: &[f32]) -> Result<(), AudioPipelineError> {
     if !CONFIG.debug_audio_resampling { return Ok(()); }
 
+    let file = std::fs::File::create("debug.wav")
+        .map_err(|e| AudioPipelineError::AudioDebugError(format!("Failed to create debug.wav: {}", e)))?;
+    write_wav(std::io::BufWriter::new(file), samples)
+}
+
+/// Encodes a 16 kHz mono buffer as a 32-bit float WAV.
+pub fn write_wav<W: std::io::Write + std::io::Seek>(out: W, samples: &[f32]) -> Result<(), AudioPipelineError> {
     let spec = hound::WavSpec {
         channels: 1,
         sample_rate: 16_000,
         bits_per_sample: 32,
         sample_format: hound::SampleFormat::Float,
     };
-    let mut writer = hound::WavWriter::create("debug.wav", spec)
-        .map_err(|e| AudioPipelineError::AudioDebugError(format!("Failed to create WAV writer: {}", e)))?;
+    let mut writer = hound::WavWriter::new(out, spec)
+        .map_err(|e| AudioPipelineError::WavEncode(format!("Failed to create WAV writer: {}", e)))?;
     for &sample in samples {
         writer.write_sample(sample)
-            .map_err(|e| AudioPipelineError::AudioDebugError(format!("Failed to write debug audio into file: {}", e)))?;
+            .map_err(|e| AudioPipelineError::WavEncode(format!("Failed to write audio into WAV: {}", e)))?;
     }
     writer.finalize()
-        .map_err(|e| AudioPipelineError::AudioDebugError(format!("Failed to finalize WAV file: {}", e)))?;
+        .map_err(|e| AudioPipelineError::WavEncode(format!("Failed to finalize WAV file: {}", e)))?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{audio::transcriber::FakeTranscriber, config::FilterConfig};
+
+    fn worker(reject_patterns: &[&str]) -> SttWorker {
+        let glossary: Glossary = toml::from_str(r#"
+            terms = ["FAKE"]
+
+            [replace]
+            "of 1.00s audio" = "in sector two"
+        "#).unwrap();
+        let filter = TranscriptFilter::new(&FilterConfig {
+            reject_patterns: reject_patterns.iter().map(|p| p.to_string()).collect(),
+            ..Default::default()
+        });
+
+        SttWorker { transcriber: Box::new(FakeTranscriber), glossary, filter }
+    }
+
+    fn process(worker: &mut SttWorker, audio: Vec<f32>, partial: bool) -> Option<SttMessage> {
+        let options = TranscribeOptions { language: Some("en".to_string()), translate: false };
+        let cancel = CancelToken::new(Arc::new(AtomicU64::new(7)), 7);
+        worker.process(&SttRequest::new(audio, partial, 7, options), &cancel)
+    }
+
+    #[test]
+    fn final_result_has_glossary_applied() {
+        let msg = process(&mut worker(&[]), vec![0.1; 16_000], false).unwrap();
+
+        assert!(matches!(msg.msg_type, SttMessageType::TranscriptionResult));
+        assert_eq!(msg.content, "FAKE transcription in sector two");
+        assert_eq!(msg.language.as_deref(), Some("en"));
+        assert_eq!(msg.generation, 7);
+    }
+
+    #[test]
+    fn partial_request_gives_partial_result() {
+        let msg = process(&mut worker(&[]), vec![0.1; 8_000], true).unwrap();
+
+        assert!(matches!(msg.msg_type, SttMessageType::PartialResult));
+        assert_eq!(msg.content, "FAKE transcription of 0.50s audio");
+    }
+
+    #[test]
+    fn junk_transcript_becomes_no_speech() {
+        let mut worker = worker(&["fake transcription*"]);
+
+        let msg = process(&mut worker, vec![0.1; 16_000], false).unwrap();
+        assert!(matches!(msg.msg_type, SttMessageType::NoSpeech));
+        assert!(msg.content.is_empty());
+
+        let msg = process(&mut worker, vec![0.1; 16_000], true).unwrap();
+        assert!(matches!(msg.msg_type, SttMessageType::PartialResult));
+        assert!(msg.content.is_empty());
+    }
+
+    #[test]
+    fn empty_recording_is_no_speech() {
+        let mut worker = worker(&[]);
+
+        let msg = process(&mut worker, Vec::new(), false).unwrap();
+        assert!(matches!(msg.msg_type, SttMessageType::NoSpeech));
+        assert_eq!(msg.generation, 7);
+
+        assert!(process(&mut worker, Vec::new(), true).is_none());
+    }
+}
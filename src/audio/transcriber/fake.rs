@@ -0,0 +1,16 @@
+use crate::audio::{speech_to_text::{SttMessage, SttMessageType}, transcriber::{CancelToken, TranscribeOptions, Transcriber}};
+
+/// Deterministic backend that doesn't need a model file. The result only depends
+/// on the length of the buffer, which makes it handy for testing the rest of the pipeline.
+pub struct FakeTranscriber;
+
+impl Transcriber for FakeTranscriber {
+    fn transcribe(&mut self, audio: &[f32], options: &TranscribeOptions, _cancel: &CancelToken) -> SttMessage {
+        let secs = audio.len() as f32 / 16_000.0;
+        let task = if options.translate { "translation" } else { "transcription" };
+        SttMessage::new(
+            SttMessageType::TranscriptionResult,
+            format!("fake {} of {:.2}s audio", task, secs)
+        ).with_language(options.language.clone())
+    }
+}
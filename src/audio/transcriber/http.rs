@@ -0,0 +1,116 @@
+use std::{io::Cursor, time::Duration};
+use serde::Deserialize;
+use ureq::Agent;
+
+use crate::{audio::{AudioPipelineError, speech_to_text::{SttMessage, SttMessageType, write_wav}, transcriber::{CancelToken, TranscribeOptions, Transcriber}}, config::HttpBackendConfig};
+
+const BOUNDARY: &str = "----lfs-stt-boundary";
+
+#[derive(Deserialize)]
+struct TranscriptionResponse {
+    text: String,
+    language: Option<String>,
+}
+
+/// Sends audio to an OpenAI compatible `/v1/audio/transcriptions` endpoint,
+/// e.g. a whisper.cpp or faster-whisper server running on another machine.
+///
+/// Cancelling can't interrupt a request that is already in flight, it keeps the
+/// STT task busy until the server answers or `timeout_secs` runs out.
+pub struct HttpTranscriber {
+    agent: Agent,
+    config: HttpBackendConfig,
+    prompt: Option<String>,
+}
+
+impl HttpTranscriber {
+    pub fn new(config: HttpBackendConfig, prompt: Option<String>) -> Self {
+        let agent: Agent = Agent::config_builder()
+            .timeout_global(Some(Duration::from_secs(config.timeout_secs)))
+            .build()
+            .into();
+
+        Self { agent, config, prompt }
+    }
+
+    fn build_body(&self, wav: &[u8], options: &TranscribeOptions) -> Vec<u8> {
+        let mut body = Vec::with_capacity(wav.len() + 512);
+        let mut push_field = |name: &str, value: &str| {
+            body.extend_from_slice(format!(
+                "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n{value}\r\n"
+            ).as_bytes());
+        };
+        if !self.config.model.is_empty() {
+            push_field("model", &self.config.model);
+        }
+        if let Some(language) = &options.language {
+            push_field("language", language);
+        }
+        if let Some(prompt) = &self.prompt {
+            push_field("prompt", prompt);
+        }
+        if options.translate {
+            // whisper.cpp server only knows the flag, OpenAI style servers use translate_url instead.
+            push_field("translate", "true");
+        }
+        push_field("response_format", "verbose_json");
+
+        body.extend_from_slice(format!(
+            "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"audio.wav\"\r\nContent-Type: audio/wav\r\n\r\n"
+        ).as_bytes());
+        body.extend_from_slice(wav);
+        body.extend_from_slice(format!("\r\n--{BOUNDARY}--\r\n").as_bytes());
+        body
+    }
+
+    fn request(&self, audio: &[f32], options: &TranscribeOptions) -> Result<TranscriptionResponse, AudioPipelineError> {
+        let mut wav = Vec::new();
+        write_wav(Cursor::new(&mut wav), audio)?;
+
+        let url = match (&self.config.translate_url, options.translate) {
+            (Some(translate_url), true) => translate_url,
+            _ => &self.config.url,
+        };
+        let mut request = self.agent.post(url)
+            .header("Content-Type", format!("multipart/form-data; boundary={BOUNDARY}"));
+        if let Some(api_key) = &self.config.api_key {
+            request = request.header("Authorization", format!("Bearer {api_key}"));
+        }
+
+        let mut response = request.send(self.build_body(&wav, options))?;
+        let response: TranscriptionResponse = response.body_mut().read_json()?;
+
+        Ok(response)
+    }
+}
+
+impl Transcriber for HttpTranscriber {
+    fn transcribe(&mut self, audio: &[f32], options: &TranscribeOptions, cancel: &CancelToken) -> SttMessage {
+        if cancel.is_cancelled() {
+            return cancelled();
+        }
+        let result = self.request(audio, options);
+        // Whatever the server said, it's no longer wanted.
+        if cancel.is_cancelled() {
+            return cancelled();
+        }
+
+        match result {
+            Ok(response) => {
+                // Servers report either the ISO code or the full language name, fall back
+                // to the requested language when it's missing.
+                let language = response.language.or_else(|| options.language.clone());
+                SttMessage::new(SttMessageType::TranscriptionResult, response.text.trim().to_string())
+                    .with_language(language)
+            },
+            Err(err) => SttMessage::new(
+                SttMessageType::TranscriptionError,
+                format!("❌ Transcription error: {:?}", err)
+            ),
+        }
+    }
+}
+
+fn cancelled() -> SttMessage {
+    SttMessage::new(SttMessageType::TranscriptionError, "Transcription cancelled".to_string())
+}
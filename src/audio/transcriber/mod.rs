@@ -0,0 +1,69 @@
+use std::sync::{Arc, atomic::{AtomicU64, Ordering}};
+
+use crate::{audio::{AudioPipelineError, speech_to_text::SttMessage}, config::{ChatChannel, SttBackend}, global::CONFIG};
+
+mod fake;
+mod http;
+mod whisper;
+
+#[cfg(test)]
+pub use fake::FakeTranscriber;
+
+/// Per-request settings, derived from the active chat channel.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TranscribeOptions {
+    /// ISO language code, or None to let the backend detect it.
+    pub language: Option<String>,
+    /// Translate the speech into English instead of transcribing it as spoken.
+    pub translate: bool,
+}
+
+impl TranscribeOptions {
+    pub fn for_channel(channel: &ChatChannel) -> Self {
+        let language = channel.language.as_ref().unwrap_or(&CONFIG.language);
+        Self {
+            language: match language.as_str() {
+                "auto" => None,
+                code => Some(code.to_string()),
+            },
+            translate: channel.translate,
+        }
+    }
+}
+
+/// Tells a backend that the request it is working on is no longer wanted.
+/// A request is cancelled once the pipeline generation moves past the one it was recorded in.
+#[derive(Clone)]
+pub struct CancelToken {
+    generation: Arc<AtomicU64>,
+    expected: u64,
+}
+
+impl CancelToken {
+    pub fn new(generation: Arc<AtomicU64>, expected: u64) -> Self {
+        Self { generation, expected }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.generation.load(Ordering::Relaxed) != self.expected
+    }
+}
+
+/// A speech-to-text engine. Takes a 16 kHz mono buffer and produces the message
+/// that is forwarded to the UI. Backends that can stop early should check `cancel`.
+pub trait Transcriber: Send {
+    fn transcribe(&mut self, audio: &[f32], options: &TranscribeOptions, cancel: &CancelToken) -> SttMessage;
+}
+
+/// Builds the backend selected by `stt_backend` in the config.
+/// `prompt` is passed to backends that support biasing the model with context.
+pub fn from_config(prompt: Option<String>) -> Result<Box<dyn Transcriber>, AudioPipelineError> {
+    match CONFIG.stt_backend {
+        SttBackend::Whisper => Ok(Box::new(whisper::WhisperTranscriber::new(prompt)?)),
+        SttBackend::Http => {
+            let config = CONFIG.http_backend.clone().ok_or(AudioPipelineError::MissingBackendConfig)?;
+            Ok(Box::new(http::HttpTranscriber::new(config, prompt)))
+        },
+        SttBackend::Fake => Ok(Box::new(fake::FakeTranscriber)),
+    }
+}
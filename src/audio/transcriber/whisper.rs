@@ -0,0 +1,101 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}};
+use tracing::debug;
+use whisper_rs::{FullParams, SamplingStrategy, WhisperContext, WhisperContextParameters, WhisperState, install_logging_hooks};
+
+use crate::{audio::{AudioPipelineError, speech_to_text::{SttMessage, SttMessageType}, transcriber::{CancelToken, TranscribeOptions, Transcriber}}, config::WhisperSampling, global::CONFIG};
+
+pub struct WhisperTranscriber {
+    state: WhisperState,
+    prompt: Option<String>,
+    /// whisper-rs never frees the strings handed to FullParams, so they are built once per options value.
+    params: HashMap<TranscribeOptions, FullParams<'static, 'static>>,
+    /// Request currently being transcribed, checked by the abort callback of the cached params.
+    cancel: Arc<Mutex<Option<CancelToken>>>,
+}
+
+impl WhisperTranscriber {
+    pub fn new(prompt: Option<String>) -> Result<Self, AudioPipelineError> {
+        install_logging_hooks();
+        let mut params = WhisperContextParameters::new();
+        params.use_gpu(CONFIG.use_gpu);
+        // check if model path exists:
+        if !std::path::Path::new(&CONFIG.model_path).exists() {
+            return Err(AudioPipelineError::ModelNotFound);
+        }
+        let whisper_ctx = WhisperContext::new_with_params(CONFIG.model_path.as_str(), params)?;
+        let state = whisper_ctx.create_state()?;
+
+        Ok(Self { state, prompt, params: HashMap::new(), cancel: Arc::new(Mutex::new(None)) })
+    }
+
+    fn full_params(&self, options: &TranscribeOptions) -> FullParams<'static, 'static> {
+        let whisper = &CONFIG.whisper;
+        let strategy = match whisper.sampling {
+            WhisperSampling::Greedy => SamplingStrategy::Greedy { best_of: whisper.best_of as i32 },
+            WhisperSampling::BeamSearch => SamplingStrategy::BeamSearch { beam_size: whisper.beam_size as i32, patience: -1.0 },
+        };
+        let mut full_params = FullParams::new(strategy);
+        if let Some(threads) = whisper.threads {
+            full_params.set_n_threads(threads as i32);
+        }
+        full_params.set_temperature(whisper.temperature);
+        full_params.set_temperature_inc(whisper.temperature_inc);
+        full_params.set_suppress_nst(whisper.suppress_non_speech);
+        full_params.set_no_speech_thold(whisper.no_speech_threshold);
+        if let Some(prompt) = &self.prompt {
+            full_params.set_initial_prompt(prompt);
+        }
+        // The params are cached, so the language has to be borrowed from whisper's own static table.
+        let language = options.language.as_deref()
+            .and_then(whisper_rs::get_lang_id)
+            .and_then(whisper_rs::get_lang_str)
+            .unwrap_or("auto");
+        full_params.set_language(Some(language));
+        full_params.set_translate(options.translate);
+        let cancel = self.cancel.clone();
+        full_params.set_abort_callback_safe(move || {
+            cancel.lock().is_ok_and(|cancel| cancel.as_ref().is_some_and(CancelToken::is_cancelled))
+        });
+        full_params.set_print_special(false);
+        full_params.set_print_progress(false);
+        full_params.set_print_realtime(false);
+        full_params.set_print_timestamps(false);
+        full_params
+    }
+}
+
+impl Transcriber for WhisperTranscriber {
+    fn transcribe(&mut self, audio: &[f32], options: &TranscribeOptions, cancel: &CancelToken) -> SttMessage {
+        if !self.params.contains_key(options) {
+            let params = self.full_params(options);
+            self.params.insert(options.clone(), params);
+        }
+        if let Ok(mut current) = self.cancel.lock() {
+            *current = Some(cancel.clone());
+        }
+
+        if let Err(err) = self.state.full(self.params[options].clone(), audio) {
+            return SttMessage::new(
+                SttMessageType::TranscriptionError,
+                format!("❌ Transcription error: {:?}", err)
+            );
+        }
+
+        let mut text = String::new();
+        let n_segments = self.state.full_n_segments();
+        for i in 0..n_segments {
+            let Some(segment) = self.state.get_segment(i) else { continue; };
+            let Ok(segment_text) = segment.to_str() else { continue; };
+            // Whisper makes things up for silence and noise, drop segments it thinks contain no speech.
+            if segment.no_speech_probability() > CONFIG.filter.max_no_speech_prob {
+                debug!("Skipping segment without speech: {}", segment_text);
+                continue;
+            }
+            text.push_str(segment_text);
+        }
+
+        let language = whisper_rs::get_lang_str(self.state.full_lang_id_from_state()).map(str::to_string);
+        SttMessage::new(SttMessageType::TranscriptionResult, text.trim().to_string())
+            .with_language(language)
+    }
+}
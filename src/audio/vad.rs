@@ -0,0 +1,299 @@
+use std::{sync::{Arc, atomic::{AtomicBool, Ordering}}};
+use tokio::{sync::mpsc::{self, Receiver, Sender}, task::JoinHandle};
+use tracing::debug;
+
+use crate::{audio::{AudioPipelineError, audio_pipeline::{CaptureMsg, PipelineEvent}}, global::CONFIG};
+
+/// 30 ms at 16 kHz.
+const FRAME_LEN: usize = 480;
+/// Silence kept around speech when trimming, so word edges aren't clipped.
+const TRIM_PADDING_SAMPLES: usize = 16_000 / 5;
+/// Continuous speech required to start a hands-free recording, filters out clicks and bumps.
+const MIN_SPEECH_SAMPLES: usize = 3 * FRAME_LEN;
+/// Used in hands-free mode when `vad.end_of_utterance_ms` is disabled.
+const HANDS_FREE_END_OF_UTTERANCE_MS: u64 = 1000;
+
+pub fn db_to_linear(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+pub fn rms(samples: &[f32]) -> f32 {
+    if samples.is_empty() { return 0.0; }
+    (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+}
+
+/// Energy based voice activity detector working on 30 ms frames.
+pub struct VoiceDetector {
+    threshold: f32,
+    frame: Vec<f32>,
+    speech_detected: bool,
+    speech_samples: usize,
+    silence_samples: usize,
+}
+
+impl VoiceDetector {
+    pub fn new(threshold_db: f32) -> Self {
+        Self {
+            threshold: db_to_linear(threshold_db),
+            frame: Vec::with_capacity(FRAME_LEN),
+            speech_detected: false,
+            speech_samples: 0,
+            silence_samples: 0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.frame.clear();
+        self.speech_detected = false;
+        self.speech_samples = 0;
+        self.silence_samples = 0;
+    }
+
+    /// Whether any speech has been seen since the last reset.
+    pub fn speech_detected(&self) -> bool {
+        self.speech_detected
+    }
+
+    /// Samples of continuous speech up to the latest frame.
+    pub fn speech_samples(&self) -> usize {
+        self.speech_samples
+    }
+
+    /// Samples of continuous silence since the last speech frame.
+    pub fn silence_samples(&self) -> usize {
+        self.silence_samples
+    }
+
+    pub fn process(&mut self, samples: &[f32]) {
+        for &sample in samples {
+            self.frame.push(sample);
+            if self.frame.len() < FRAME_LEN {
+                continue;
+            }
+
+            if rms(&self.frame) >= self.threshold {
+                self.speech_detected = true;
+                self.speech_samples += FRAME_LEN;
+                self.silence_samples = 0;
+            } else {
+                self.speech_samples = 0;
+                self.silence_samples += FRAME_LEN;
+            }
+            self.frame.clear();
+        }
+    }
+}
+
+/// RMS level of the loudest frame in `buffer`.
+pub fn loudest_frame_rms(buffer: &[f32]) -> f32 {
+    buffer.chunks(FRAME_LEN).map(rms).fold(0.0, f32::max)
+}
+
+/// Returns the part of `buffer` between the first and last speech frame, with some padding.
+/// Returns an empty slice if there is no speech at all.
+pub fn trim_silence(buffer: &[f32], threshold_db: f32) -> &[f32] {
+    let threshold = db_to_linear(threshold_db);
+    let is_speech = |frame: &[f32]| rms(frame) >= threshold;
+
+    let first = buffer.chunks(FRAME_LEN).position(is_speech);
+    let last = buffer.chunks(FRAME_LEN).rposition(is_speech);
+    match (first, last) {
+        (Some(first), Some(last)) => {
+            let start = (first * FRAME_LEN).saturating_sub(TRIM_PADDING_SAMPLES);
+            let end = ((last + 1) * FRAME_LEN + TRIM_PADDING_SAMPLES).min(buffer.len());
+            &buffer[start..end]
+        },
+        _ => &buffer[..0],
+    }
+}
+
+/// Watches resampled audio and stops the recording once the speaker has been
+/// silent for `vad.end_of_utterance_ms`. In hands-free mode (`is_listening`) it
+/// also starts the recording as soon as speech is detected.
+/// Audio outside of recordings is passed on as well, the capture task keeps it as pre-roll.
+pub async fn init(
+    mut audio_rx: Receiver<CaptureMsg>,
+    is_recording: Arc<AtomicBool>,
+    is_listening: Arc<AtomicBool>,
+    events_tx: Sender<PipelineEvent>,
+) -> Result<(Receiver<CaptureMsg>, JoinHandle<Result<(), AudioPipelineError>>), AudioPipelineError> {
+    let (tx, rx) = mpsc::channel::<CaptureMsg>(10);
+    let handle = tokio::spawn(async move {
+        let mut detector = VoiceDetector::new(CONFIG.vad.threshold_db);
+        let end_of_utterance = (16 * CONFIG.vad.end_of_utterance_ms) as usize;
+        let hands_free_end_of_utterance = match end_of_utterance {
+            0 => (16 * HANDS_FREE_END_OF_UTTERANCE_MS) as usize,
+            samples => samples,
+        };
+        // Set by the in-band Start, so it agrees with the capture task on where the recording begins.
+        let mut in_recording = false;
+        let mut hands_free = false;
+
+        while let Some(msg) = audio_rx.recv().await {
+            match msg {
+                CaptureMsg::Audio(samples) => {
+                    if !in_recording {
+                        if is_listening.load(Ordering::Relaxed) {
+                            detector.process(&samples);
+                            // Left to the Start on its way if the user started a recording meanwhile.
+                            if detector.speech_samples() >= MIN_SPEECH_SAMPLES && !is_recording.swap(true, Ordering::Relaxed) {
+                                debug!("Speech detected, starting recording");
+                                // The capture task starts the buffer from its pre-roll, this chunk included.
+                                in_recording = true;
+                                hands_free = true;
+                                let _ = events_tx.send(PipelineEvent::RecordingStarted).await;
+                                if tx.send(CaptureMsg::Start).await.is_err() {
+                                    break;
+                                }
+                            }
+                        } else {
+                            // Idle audio or leftovers from a stopped recording.
+                            detector.reset();
+                        }
+                        if tx.send(CaptureMsg::Audio(samples)).await.is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+
+                    let end_of_utterance = if hands_free { hands_free_end_of_utterance } else { end_of_utterance };
+                    if end_of_utterance > 0 {
+                        detector.process(&samples);
+                    }
+                    if tx.send(CaptureMsg::Audio(samples)).await.is_err() {
+                        break;
+                    }
+
+                    if end_of_utterance > 0
+                        && detector.speech_detected()
+                        && detector.silence_samples() >= end_of_utterance
+                        && is_recording.swap(false, Ordering::Relaxed)
+                    {
+                        debug!("End of utterance detected, stopping recording");
+                        detector.reset();
+                        in_recording = false;
+                        let _ = events_tx.send(PipelineEvent::RecordingStopped).await;
+                        if tx.send(CaptureMsg::Stop).await.is_err() {
+                            break;
+                        }
+                    }
+                },
+                CaptureMsg::Start => {
+                    if !in_recording {
+                        in_recording = true;
+                        hands_free = false;
+                        detector.reset();
+                    }
+                    if tx.send(CaptureMsg::Start).await.is_err() {
+                        break;
+                    }
+                },
+                CaptureMsg::Stop | CaptureMsg::Reset | CaptureMsg::Cancel => {
+                    detector.reset();
+                    in_recording = false;
+                    if tx.send(msg).await.is_err() {
+                        break;
+                    }
+                },
+                CaptureMsg::Reconfigure(_) => { /* Handled by the resampler */ },
+            }
+        }
+
+        Ok(())
+    });
+
+    Ok((rx, handle))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLD_DB: f32 = -40.0;
+
+    fn frames(amplitude: f32, count: usize) -> Vec<f32> {
+        vec![amplitude; count * FRAME_LEN]
+    }
+
+    fn recording(parts: &[(f32, usize)]) -> Vec<f32> {
+        parts.iter().flat_map(|&(amplitude, count)| frames(amplitude, count)).collect()
+    }
+
+    #[test]
+    fn trim_keeps_padding_around_speech() {
+        let buffer = recording(&[(0.0, 20), (0.5, 10), (0.0, 20)]);
+        let trimmed = trim_silence(&buffer, THRESHOLD_DB);
+
+        assert_eq!(trimmed.len(), 10 * FRAME_LEN + 2 * TRIM_PADDING_SAMPLES);
+        assert_eq!(trimmed[TRIM_PADDING_SAMPLES - 1], 0.0);
+        assert_eq!(trimmed[TRIM_PADDING_SAMPLES], 0.5);
+        assert_eq!(trimmed[trimmed.len() - TRIM_PADDING_SAMPLES - 1], 0.5);
+        assert_eq!(trimmed[trimmed.len() - TRIM_PADDING_SAMPLES], 0.0);
+    }
+
+    #[test]
+    fn trim_stops_at_the_buffer_edges() {
+        let buffer = recording(&[(0.5, 2), (0.0, 2), (0.5, 2)]);
+        assert_eq!(trim_silence(&buffer, THRESHOLD_DB), &buffer[..]);
+
+        // Speech in the last, incomplete frame.
+        let mut buffer = recording(&[(0.0, 20)]);
+        buffer.extend([0.5; 100]);
+        let trimmed = trim_silence(&buffer, THRESHOLD_DB);
+        assert_eq!(trimmed.len(), 100 + TRIM_PADDING_SAMPLES);
+        assert_eq!(trimmed, &buffer[buffer.len() - trimmed.len()..]);
+    }
+
+    #[test]
+    fn trim_without_speech_is_empty() {
+        assert!(trim_silence(&recording(&[(0.001, 20)]), THRESHOLD_DB).is_empty());
+        assert!(trim_silence(&[], THRESHOLD_DB).is_empty());
+    }
+
+    #[test]
+    fn loudest_frame_finds_short_bursts() {
+        let buffer = recording(&[(0.0, 20), (0.5, 1), (0.1, 20)]);
+        assert!((loudest_frame_rms(&buffer) - 0.5).abs() < 1e-6);
+        assert!((rms(&buffer) - 0.5).abs() > 0.1);
+        assert_eq!(loudest_frame_rms(&[]), 0.0);
+    }
+
+    #[test]
+    fn detector_counts_whole_frames_only() {
+        let mut detector = VoiceDetector::new(THRESHOLD_DB);
+        detector.process(&[0.5; FRAME_LEN - 1]);
+        assert!(!detector.speech_detected());
+        assert_eq!(detector.speech_samples(), 0);
+
+        detector.process(&[0.5; 2]);
+        assert!(detector.speech_detected());
+        assert_eq!(detector.speech_samples(), FRAME_LEN);
+    }
+
+    #[test]
+    fn detector_needs_continuous_speech_to_trigger() {
+        let mut detector = VoiceDetector::new(THRESHOLD_DB);
+        // A click shorter than MIN_SPEECH_SAMPLES followed by silence starts over.
+        detector.process(&recording(&[(0.5, 2), (0.0, 1), (0.5, 2)]));
+        assert!(detector.speech_samples() < MIN_SPEECH_SAMPLES);
+
+        detector.process(&frames(0.5, 1));
+        assert!(detector.speech_samples() >= MIN_SPEECH_SAMPLES);
+    }
+
+    #[test]
+    fn detector_counts_silence_after_speech() {
+        let mut detector = VoiceDetector::new(THRESHOLD_DB);
+        detector.process(&recording(&[(0.5, 5), (0.0, 4)]));
+        assert!(detector.speech_detected());
+        assert_eq!(detector.speech_samples(), 0);
+        assert_eq!(detector.silence_samples(), 4 * FRAME_LEN);
+
+        // A single speech frame restarts the silence count, speech stays detected until reset.
+        detector.process(&recording(&[(0.5, 1), (0.0, 1)]));
+        assert_eq!(detector.silence_samples(), FRAME_LEN);
+        detector.reset();
+        assert!(!detector.speech_detected());
+        assert_eq!(detector.silence_samples(), 0);
+    }
+}
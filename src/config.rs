@@ -44,27 +44,245 @@ impl From<LogLevel> for LevelFilter {
     }
 }
 
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SttBackend {
+    #[default]
+    Whisper,
+    Http,
+    Fake,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct HttpBackendConfig {
+    pub url: String,
+    /// `/v1/audio/translations` style endpoint used for channels with `translate = true`.
+    pub translate_url: Option<String>,
+    #[serde(default = "default_http_timeout_secs")]
+    pub timeout_secs: u64,
+    #[serde(default)]
+    pub model: String,
+    pub api_key: Option<String>,
+}
+
+// The config is logged at startup, keep the key out of it.
+impl std::fmt::Debug for HttpBackendConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HttpBackendConfig")
+            .field("url", &self.url)
+            .field("translate_url", &self.translate_url)
+            .field("timeout_secs", &self.timeout_secs)
+            .field("model", &self.model)
+            .field("api_key", &self.api_key.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WhisperSampling {
+    Greedy,
+    BeamSearch,
+}
+
+/// Decoding parameters for the local whisper backend.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct WhisperConfig {
+    pub sampling: WhisperSampling,
+    /// Candidates considered per token with greedy sampling.
+    pub best_of: u8,
+    /// Beam width with beam search sampling.
+    pub beam_size: u8,
+    /// Initial decoding temperature.
+    pub temperature: f32,
+    /// Temperature step used to retry a failed decode, 0 disables the fallback.
+    pub temperature_inc: f32,
+    /// Number of CPU threads, whisper picks a default when unset.
+    pub threads: Option<u16>,
+    /// Suppress non-speech tokens like "(engine revving)" or "[music]".
+    pub suppress_non_speech: bool,
+    /// Probability above which whisper itself treats a segment as silence, but only
+    /// if it is also unsure about the decoded text. Applied while decoding.
+    pub no_speech_threshold: f32,
+    /// Text fed to the decoder as previous context.
+    pub initial_prompt: String,
+}
+
+impl Default for WhisperConfig {
+    fn default() -> Self {
+        WhisperConfig {
+            sampling: WhisperSampling::Greedy,
+            best_of: 8,
+            beam_size: 5,
+            temperature: 0.0,
+            temperature_inc: 0.2,
+            threads: None,
+            suppress_non_speech: false,
+            no_speech_threshold: 0.6,
+            initial_prompt: String::new(),
+        }
+    }
+}
+
+/// Voice activity detection settings.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct VadConfig {
+    /// Frame level (dBFS) above which audio counts as speech.
+    pub threshold_db: f32,
+    /// Cut leading and trailing silence before transcribing.
+    pub trim_silence: bool,
+    /// Stop recording after this much silence following speech, 0 disables.
+    pub end_of_utterance_ms: u64,
+}
+
+impl Default for VadConfig {
+    fn default() -> Self {
+        VadConfig {
+            threshold_db: -45.0,
+            trim_silence: false,
+            end_of_utterance_ms: 0,
+        }
+    }
+}
+
+/// Trade-off between CPU usage and audio quality when converting to 16 kHz.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResamplerQuality {
+    /// Cubic polynomial interpolation.
+    Fast,
+    /// 128 tap sinc interpolation.
+    #[default]
+    Balanced,
+    /// 256 tap sinc interpolation.
+    HighQuality,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Normalization {
+    #[default]
+    Off,
+    Peak,
+    Rms,
+}
+
+/// Audio cleanup applied to the resampled signal before VAD and transcription.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DspConfig {
+    /// Remove rumble (engine, wind) below `high_pass_cutoff_hz`.
+    pub high_pass: bool,
+    pub high_pass_cutoff_hz: f32,
+    /// Mute the signal while it stays below `noise_gate_threshold_db`.
+    pub noise_gate: bool,
+    pub noise_gate_threshold_db: f32,
+    /// Subtract a running estimate of stationary background noise.
+    pub noise_suppression: bool,
+    /// Scale the finished recording so its peak or RMS level hits `normalize_target_db`.
+    pub normalize: Normalization,
+    pub normalize_target_db: f32,
+}
+
+impl Default for DspConfig {
+    fn default() -> Self {
+        DspConfig {
+            high_pass: false,
+            high_pass_cutoff_hz: 100.0,
+            noise_gate: false,
+            noise_gate_threshold_db: -50.0,
+            noise_suppression: false,
+            normalize: Normalization::Off,
+            normalize_target_db: -3.0,
+        }
+    }
+}
+
+/// Rules for dropping transcripts that contain no actual speech.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct FilterConfig {
+    /// Transcripts matching one of these (case-insensitive, `*` as wildcard) are dropped.
+    /// Bracketed tags are removed first, a transcript of nothing but tags is always dropped.
+    pub reject_patterns: Vec<String>,
+    /// Recordings whose loudest part stays below this level (dBFS) are not transcribed.
+    pub min_speech_db: f32,
+    /// Whisper segments more likely than this to contain no speech are dropped from the result,
+    /// however confident whisper is about their text. See `WhisperConfig::no_speech_threshold`.
+    pub max_no_speech_prob: f32,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        FilterConfig {
+            // Phrases whisper makes up for noise could also be meant, so none by default.
+            reject_patterns: Vec::new(),
+            min_speech_db: -60.0,
+            max_no_speech_prob: 0.8,
+        }
+    }
+}
+
+fn default_http_timeout_secs() -> u64 { 30 }
+
+fn default_language() -> String { "en".to_string() }
+
+fn is_valid_language(language: &str) -> bool {
+    language == "auto" || whisper_rs::get_lang_id(language).is_some()
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
     pub btn_id_offset: u8,
     pub debug_log_level: LogLevel,
     pub chat_channels: Vec<ChatChannel>,
     pub debug_audio_resampling: bool,
+    #[serde(default)]
+    pub dsp: DspConfig,
+    #[serde(default)]
+    pub filter: FilterConfig,
+    pub glossary_path: Option<String>,
+    pub http_backend: Option<HttpBackendConfig>,
     pub insim_host: String,
     pub insim_port: String,
+    pub input_device: Option<String>,
+    pub input_channels: Option<Vec<u16>>,
+    #[serde(default = "default_language")]
+    pub language: String,
     pub message_preview_timeout_secs: u64,
     pub model_path: String,
+    #[serde(default)]
+    pub partial_transcript_interval_ms: u64,
+    #[serde(default)]
+    pub pre_roll_ms: u64,
     pub recording_timeout_secs: u8,
+    #[serde(default)]
+    pub release_mic_when_idle: bool,
+    #[serde(default)]
+    pub resampler_quality: ResamplerQuality,
+    #[serde(default)]
+    pub stt_backend: SttBackend,
     pub ui_offset_left: u8,
     pub ui_offset_top: u8,
     pub ui_scale: u8,
     pub use_gpu: bool,
+    #[serde(default)]
+    pub vad: VadConfig,
+    #[serde(default)]
+    pub whisper: WhisperConfig,
 }
 
 #[derive(Clone, Debug, serde::Deserialize)]
 pub struct ChatChannel {
     pub display: String,
     pub prefix: String,
+    /// Overrides the global `language` for messages recorded on this channel.
+    pub language: Option<String>,
+    /// Translate messages recorded on this channel into English.
+    #[serde(default)]
+    pub translate: bool,
 }
 
 impl PartialEq for ChatChannel {
@@ -76,8 +294,8 @@ impl PartialEq for ChatChannel {
 
 impl Display for Config {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Config {{ insim_host: {}, insim_port: {}, chat_channels: {:?}, model_path: {}, message_preview_timeout_secs: {}, recording_timeout_secs: {}, ui_scale: {}, ui_offset_top: {}, ui_offset_left: {}, btn_id_offset: {}, debug_audio_resampling: {}, use_gpu: {} }}",
-            self.insim_host, self.insim_port, self.chat_channels, self.model_path, self.message_preview_timeout_secs, self.recording_timeout_secs, self.ui_scale, self.ui_offset_top, self.ui_offset_left, self.btn_id_offset, self.debug_audio_resampling, self.use_gpu)
+        write!(f, "Config {{ insim_host: {}, insim_port: {}, input_device: {:?}, input_channels: {:?}, language: {}, chat_channels: {:?}, model_path: {}, partial_transcript_interval_ms: {}, pre_roll_ms: {}, message_preview_timeout_secs: {}, recording_timeout_secs: {}, release_mic_when_idle: {}, resampler_quality: {:?}, stt_backend: {:?}, http_backend: {:?}, ui_scale: {}, ui_offset_top: {}, ui_offset_left: {}, btn_id_offset: {}, debug_audio_resampling: {}, glossary_path: {:?}, use_gpu: {}, dsp: {:?}, filter: {:?}, vad: {:?}, whisper: {:?} }}",
+            self.insim_host, self.insim_port, self.input_device, self.input_channels, self.language, self.chat_channels, self.model_path, self.partial_transcript_interval_ms, self.pre_roll_ms, self.message_preview_timeout_secs, self.recording_timeout_secs, self.release_mic_when_idle, self.resampler_quality, self.stt_backend, self.http_backend, self.ui_scale, self.ui_offset_top, self.ui_offset_left, self.btn_id_offset, self.debug_audio_resampling, self.glossary_path, self.use_gpu, self.dsp, self.filter, self.vad, self.whisper)
     }
 }
 
@@ -115,10 +333,106 @@ impl Config {
             return Err(ConfigError::ValidationError("Button ID offset must be between 0 and 230.".into()))
         }
 
+        if !is_valid_language(&self.language) {
+            return Err(ConfigError::ValidationError(format!("Unknown language \"{}\", use an ISO code or \"auto\".", self.language)))
+        }
+
+        if let Some(channels) = &self.input_channels {
+            if channels.is_empty() {
+                return Err(ConfigError::ValidationError("Input channels cannot be empty, remove the key to use all channels.".into()))
+            }
+            if channels.contains(&0) {
+                return Err(ConfigError::ValidationError("Input channels are numbered from 1.".into()))
+            }
+        }
+
+        if self.pre_roll_ms > 2000 {
+            return Err(ConfigError::ValidationError("Pre-roll must be between 0 and 2000 ms.".into()))
+        }
+
+        self.whisper.validate()?;
+
+        if !(-100.0..=0.0).contains(&self.vad.threshold_db) {
+            return Err(ConfigError::ValidationError("VAD threshold_db must be between -100.0 and 0.0.".into()))
+        }
+
+        self.dsp.validate()?;
+
+        if !(-100.0..=0.0).contains(&self.filter.min_speech_db) {
+            return Err(ConfigError::ValidationError("Filter min_speech_db must be between -100.0 and 0.0.".into()))
+        }
+        if !(0.0..=1.0).contains(&self.filter.max_no_speech_prob) {
+            return Err(ConfigError::ValidationError("Filter max_no_speech_prob must be between 0.0 and 1.0.".into()))
+        }
+
+        if let Some(path) = &self.glossary_path && !std::path::Path::new(path).exists() {
+            return Err(ConfigError::ValidationError(format!("Glossary file {} does not exist.", path)))
+        }
+
+        if let SttBackend::Http = self.stt_backend {
+            match &self.http_backend {
+                None => return Err(ConfigError::ValidationError("http_backend section is required when stt_backend is \"http\".".into())),
+                Some(http) if http.url.is_empty() => {
+                    return Err(ConfigError::ValidationError("HTTP backend url cannot be empty.".into()))
+                },
+                Some(http) if http.timeout_secs == 0 => {
+                    return Err(ConfigError::ValidationError("HTTP backend timeout must be greater than 0.".into()))
+                },
+                Some(_) => (),
+            }
+        }
+
         for channel in &self.chat_channels {
             if channel.display.is_empty() {
                 return Err(ConfigError::ValidationError("Chat channel display name cannot be empty.".into()))
             }
+            if let Some(language) = &channel.language && !is_valid_language(language) {
+                return Err(ConfigError::ValidationError(format!("Unknown language \"{}\" on chat channel {}.", language, channel.display)))
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl DspConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if !(20.0..=1000.0).contains(&self.high_pass_cutoff_hz) {
+            return Err(ConfigError::ValidationError("DSP high_pass_cutoff_hz must be between 20 and 1000.".into()))
+        }
+        if !(-100.0..=0.0).contains(&self.noise_gate_threshold_db) {
+            return Err(ConfigError::ValidationError("DSP noise_gate_threshold_db must be between -100.0 and 0.0.".into()))
+        }
+        if !(-40.0..=0.0).contains(&self.normalize_target_db) {
+            return Err(ConfigError::ValidationError("DSP normalize_target_db must be between -40.0 and 0.0.".into()))
+        }
+
+        Ok(())
+    }
+}
+
+impl WhisperConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.best_of == 0 {
+            return Err(ConfigError::ValidationError("Whisper best_of must be greater than 0.".into()))
+        }
+        if self.beam_size == 0 {
+            return Err(ConfigError::ValidationError("Whisper beam_size must be greater than 0.".into()))
+        }
+        if !(0.0..=1.0).contains(&self.temperature) {
+            return Err(ConfigError::ValidationError("Whisper temperature must be between 0.0 and 1.0.".into()))
+        }
+        if !(0.0..=1.0).contains(&self.temperature_inc) {
+            return Err(ConfigError::ValidationError("Whisper temperature_inc must be between 0.0 and 1.0.".into()))
+        }
+        if self.threads == Some(0) {
+            return Err(ConfigError::ValidationError("Whisper threads must be greater than 0.".into()))
+        }
+        if !(0.0..=1.0).contains(&self.no_speech_threshold) {
+            return Err(ConfigError::ValidationError("Whisper no_speech_threshold must be between 0.0 and 1.0.".into()))
+        }
+        if self.initial_prompt.contains('\0') {
+            return Err(ConfigError::ValidationError("Whisper initial_prompt cannot contain null characters.".into()))
         }
 
         Ok(())
@@ -7,16 +7,30 @@ use crate::global::CONFIG;
 #[allow(dead_code)]
 pub enum InsimEvent {
     ToggleRecording,
+    StartRecording,
+    StopRecording,
+    CancelRecording,
+    DiscardMessage,
+    ToggleHandsFree,
     AcceptMessage,
     NextChannel,
     PeviousChannel,
     IsInGame(bool),
+    /// Text entered into one of our buttons, identified by its id relative to `btn_id_offset`.
+    ButtonTypedIn(u8, String),
+    /// One of our buttons was clicked, identified by its id relative to `btn_id_offset`.
+    ButtonClicked { id: u8, right_click: bool },
 }
 
 impl InsimEvent {
     pub fn from_string(cmd: String) -> Option<InsimEvent> {
         match cmd.as_str() {
             "stt talk" => Some(InsimEvent::ToggleRecording),
+            "stt start" => Some(InsimEvent::StartRecording),
+            "stt stop" => Some(InsimEvent::StopRecording),
+            "stt cancel" => Some(InsimEvent::CancelRecording),
+            "stt discard" => Some(InsimEvent::DiscardMessage),
+            "stt handsfree" => Some(InsimEvent::ToggleHandsFree),
             "stt accept" => Some(InsimEvent::AcceptMessage),
             "stt nc" => Some(InsimEvent::NextChannel),
             "stt pc" => Some(InsimEvent::PeviousChannel),
@@ -55,7 +69,18 @@ pub async fn init_insim() -> Result<(InsimTask, Receiver<InsimEvent>, JoinHandle
                     },
                     insim::Packet::Sta(sta) => {
                         let _ = event_tx.send(InsimEvent::IsInGame(sta.flags.is_in_game())).await;
-                    }
+                    },
+                    insim::Packet::Btc(btc) => {
+                        if let Some(id) = (*btc.clickid).checked_sub(CONFIG.btn_id_offset) {
+                            let right_click = btc.cflags.contains(insim::insim::BtcFlags::RMB);
+                            let _ = event_tx.send(InsimEvent::ButtonClicked { id, right_click }).await;
+                        }
+                    },
+                    insim::Packet::Btt(btt) => {
+                        if let Some(id) = (*btt.clickid).checked_sub(CONFIG.btn_id_offset) {
+                            let _ = event_tx.send(InsimEvent::ButtonTypedIn(id, btt.text)).await;
+                        }
+                    },
                     _ => {}
                 };
             }
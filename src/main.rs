@@ -13,6 +13,10 @@ mod global;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    if std::env::args().any(|arg| arg == "--list-devices") {
+        return audio::list_input_devices().context("Failed to list audio input devices");
+    }
+
     let subscriber = FmtSubscriber::builder()
         .with_max_level(LevelFilter::from(CONFIG.debug_log_level))
         .finish();
@@ -23,7 +27,7 @@ async fn main() -> anyhow::Result<()> {
         insim_io::init_insim()
             .await
             .context("Failed to initialize insim io")?;
-    let (mut audio_pipeline, mut stt_rx, audio_pipeline_handle) =
+    let (mut audio_pipeline, mut stt_rx, mut pipeline_rx, audio_pipeline_handle) =
         audio::audio_pipeline::AudioPipeline::new()
             .await
             .context("Failed to initialize audio pipeline")?;
@@ -36,14 +40,32 @@ async fn main() -> anyhow::Result<()> {
     loop {
         // Always dispatch UI events first
         ui_context.dispatch_ui_events(insim.clone()).await;
+        audio_pipeline.set_hands_free(ui_context.is_listening());
 
         tokio::select! {
-            // Clear any UI message timeout
-            _ = ui_context.clear_message_timeout() => {},
+            // Clear the message preview or error state once it times out
+            _ = ui_context.handle_timeouts() => {},
 
             // Process STT messages
             Some(msg) = stt_rx.recv() => {
-                ui_context.handle_stt_message(msg);
+                // Late results of discarded recordings
+                if audio_pipeline.is_current(&msg) {
+                    ui_context.handle_stt_message(msg);
+                }
+            },
+
+            // Process audio pipeline events
+            Some(event) = pipeline_rx.recv() => {
+                if let Some(event) = audio_pipeline.handle_event(event) {
+                    ui_context.handle_pipeline_event(event);
+                }
+            },
+
+            // Try to reopen a lost input device
+            _ = audio_pipeline.reconnect_timeout() => {
+                if let Some(event) = audio_pipeline.reconnect().await {
+                    ui_context.handle_pipeline_event(event);
+                }
             },
 
             // Process Insim events
@@ -3,12 +3,24 @@ use insim::builder::InsimTask;
 use tokio::time::Sleep;
 use tracing::{debug, error, info};
 
-use crate::{audio::{audio_pipeline::AudioPipeline, speech_to_text::{SttMessage, SttMessageType}}, config::ChatChannel, global::CONFIG, insim_io::InsimEvent};
+use crate::{audio::{audio_pipeline::{AudioPipeline, PipelineEvent}, speech_to_text::{SttMessage, SttMessageType}}, config::ChatChannel, global::CONFIG, insim_io::InsimEvent};
 
 const MAX_MESSAGE_LEN: usize = 95;
 const STATE_ID: u8 = 0;
 const PREVIEW_ID: u8 = 1;
 const CHANNEL_ID: u8 = 2;
+const LEVEL_ID: u8 = 3;
+const COUNTDOWN_ID: u8 = 4;
+const ERROR_ID: u8 = 5;
+/// How long the error state stays visible before going back to Idle.
+const ERROR_TIMEOUT_SECS: u64 = 4;
+/// Ask LFS to fill the text entry dialog with the current button text.
+const TYPEIN_INIT_WITH_TEXT: u8 = 0x80;
+/// The countdown turns red when this many seconds are left.
+const COUNTDOWN_WARNING_SECS: u64 = 3;
+/// Segments in the input level bar, spread over LEVEL_FLOOR_DB..0 dBFS.
+const LEVEL_SEGMENTS: u8 = 10;
+const LEVEL_FLOOR_DB: f32 = -60.0;
 
 #[derive(Debug, Clone, Copy)]
 pub enum UiState {
@@ -16,24 +28,55 @@ pub enum UiState {
     Recording,
     Processing,
     Stopped,
+    MicLost,
+    /// The last transcription failed or found no speech, behaves like Idle.
+    Error,
+}
+
+/// How the message preview button behaves.
+#[derive(Debug, Clone, Copy)]
+enum PreviewMode {
+    /// Partial transcript while recording, not clickable.
+    Partial,
+    /// Click to accept, right click to edit.
+    Final,
+    /// Click opens LFS's text entry dialog.
+    Editing,
 }
 
 #[derive(Debug)]
 pub enum UiEvent {
     UpdatePreview(String),
+    UpdatePartialPreview(String),
     UpdateState(UiState),
     UpdateChannel(ChatChannel),
+    /// Input level bar, RMS and peak in segments.
+    UpdateLevel { rms: u8, peak: u8 },
+    /// Seconds left until the recording times out.
+    UpdateCountdown(u64),
+    /// Short error text above the state dot.
+    ShowError(String),
     ClearPreview,
     RemoveAllBtns,
 }
 
 pub struct UiContext {
     message_timeout: Option<Pin<Box<Sleep>>>,
+    error_timeout: Option<Pin<Box<Sleep>>>,
     state: UiState,
     message: String,
     update_queue: Vec<UiEvent>,
     chat_channels: Vec<ChatChannel>,
     active_channel: ChatChannel,
+    hands_free: bool,
+    mic_lost: bool,
+    /// Input level currently shown, None while the bar is hidden.
+    level: Option<(u8, u8)>,
+    /// Remaining recording time currently shown, None while hidden.
+    countdown: Option<u64>,
+    error_shown: bool,
+    /// The preview was right clicked, the next click edits it.
+    editing: bool,
 }
 
 impl Default for UiContext {
@@ -42,23 +85,49 @@ impl Default for UiContext {
             state: UiState::Stopped,
             message: String::from(""),
             message_timeout: None,
+            error_timeout: None,
             update_queue: vec![],
             active_channel: CONFIG.chat_channels[0].clone(),
             chat_channels: CONFIG.chat_channels.clone(),
+            hands_free: false,
+            mic_lost: false,
+            level: None,
+            countdown: None,
+            error_shown: false,
+            editing: false,
         }
     }
 }
 
 impl UiContext {
-    pub async fn clear_message_timeout(&mut self) {
-        if let Some(t) = &mut self.message_timeout {
-            t.as_mut().await;
-            self.update_queue.push(UiEvent::ClearPreview);
-            self.message.clear();
-            self.message_timeout = None;
+    /// Resolves once the message preview or the error state expires and clears it.
+    pub async fn handle_timeouts(&mut self) {
+        tokio::select! {
+            _ = wait_for(&mut self.message_timeout) => {
+                self.update_queue.push(UiEvent::ClearPreview);
+                self.message.clear();
+                self.message_timeout = None;
+            },
+            _ = wait_for(&mut self.error_timeout) => {
+                self.error_timeout = None;
+                if let UiState::Error = self.state {
+                    self.state = UiState::Idle;
+                    self.update_queue.push(UiEvent::UpdateState(self.state));
+                }
+            },
         }
     }
 
+    /// Whether the audio pipeline should be waiting for speech to start a recording.
+    pub fn is_listening(&self) -> bool {
+        self.hands_free && self.is_ready()
+    }
+
+    /// Idle, or showing an error that doesn't need to block anything.
+    fn is_ready(&self) -> bool {
+        matches!(self.state, UiState::Idle | UiState::Error)
+    }
+
     pub async fn dispatch_ui_events(&mut self, insim: InsimTask) {
         if !self.update_queue.is_empty() {
             debug!("Dispatching {} UI events", self.update_queue.len());
@@ -67,28 +136,56 @@ impl UiContext {
         while let Some(event) = self.update_queue.pop() {
             match event {
                 UiEvent::UpdatePreview(message) => {
-                    let _ = insim.send(insim::Packet::Btn(get_message_preview_btn(message))).await;
+                    // LFS only updates the text of an existing button, delete it so the width follows the message.
+                    let _ = insim.send(insim::Packet::Bfn(get_del_btn(PREVIEW_ID))).await;
+                    let mode = if self.editing { PreviewMode::Editing } else { PreviewMode::Final };
+                    let _ = insim.send(insim::Packet::Btn(get_message_preview_btn(message, mode))).await;
+                },
+                UiEvent::UpdatePartialPreview(message) => {
+                    self.editing = false;
+                    let _ = insim.send(insim::Packet::Bfn(get_del_btn(PREVIEW_ID))).await;
+                    let _ = insim.send(insim::Packet::Btn(get_message_preview_btn(message, PreviewMode::Partial))).await;
                 },
                 UiEvent::ClearPreview => {
-                    let bfn = insim::insim::Bfn {
-                        subt: insim::insim::BfnType::DelBtn,
-                        reqi: insim::identifiers::RequestId::from(1),
-                        clickid: insim::identifiers::ClickId::from(CONFIG.btn_id_offset + PREVIEW_ID),
-                        clickmax: 0,
-                        ucid: insim::identifiers::ConnectionId::LOCAL,
-                        ..Default::default()
-                    };
-                    let _ = insim.send(insim::Packet::Bfn(bfn)).await;
+                    self.editing = false;
+                    let _ = insim.send(insim::Packet::Bfn(get_del_btn(PREVIEW_ID))).await;
                 },
                 UiEvent::UpdateState(state) => {
-                    let _ = insim.send(insim::Packet::Btn(get_state_btn(state))).await;
+                    let _ = insim.send(insim::Packet::Btn(get_state_btn(state, self.hands_free))).await;
+                    if !matches!(state, UiState::Error) && self.error_shown {
+                        self.error_shown = false;
+                        let _ = insim.send(insim::Packet::Bfn(get_del_btn(ERROR_ID))).await;
+                    }
+                    if !matches!(state, UiState::Recording) {
+                        if self.level.take().is_some() {
+                            let _ = insim.send(insim::Packet::Bfn(get_del_btn(LEVEL_ID))).await;
+                        }
+                        if self.countdown.take().is_some() {
+                            let _ = insim.send(insim::Packet::Bfn(get_del_btn(COUNTDOWN_ID))).await;
+                        }
+                    }
+                },
+                UiEvent::ShowError(text) => {
+                    self.error_shown = true;
+                    let _ = insim.send(insim::Packet::Bfn(get_del_btn(ERROR_ID))).await;
+                    let _ = insim.send(insim::Packet::Btn(get_error_btn(text))).await;
+                },
+                UiEvent::UpdateCountdown(remaining) => {
+                    let _ = insim.send(insim::Packet::Btn(get_countdown_btn(remaining))).await;
+                },
+                UiEvent::UpdateLevel { rms, peak } => {
+                    let _ = insim.send(insim::Packet::Btn(get_level_btn(rms, peak))).await;
                 },
                 UiEvent::RemoveAllBtns => {
+                    self.level = None;
+                    self.countdown = None;
+                    self.error_shown = false;
+                    self.editing = false;
                     let _ = insim.send(insim::Packet::Bfn(insim::insim::Bfn{
                         subt: insim::insim::BfnType::Clear,
                         reqi: insim::identifiers::RequestId::from(1),
                         clickid: insim::identifiers::ClickId::from(CONFIG.btn_id_offset),
-                        clickmax: CONFIG.btn_id_offset + 3,
+                        clickmax: CONFIG.btn_id_offset + ERROR_ID,
                         ucid: insim::identifiers::ConnectionId::LOCAL,
                         ..Default::default()
                     })).await;
@@ -104,28 +201,212 @@ impl UiContext {
         match msg.msg_type {
             SttMessageType::TranscriptionError => {
                 error!("{}", msg);
+                self.show_error("^1Transcription failed");
+            },
+            SttMessageType::NoSpeech => {
+                info!("No speech detected.");
+                self.show_error("^3No speech detected");
             },
             SttMessageType::TranscriptionResult => {
                 info!("{}", msg);
                 self.message = msg.content;
-                self.state = UiState::Idle;
-                self.update_queue.push(UiEvent::UpdateState(self.state));
-                self.update_queue.push(UiEvent::UpdatePreview(self.message.clone()));
+                self.editing = false;
+                self.finish_processing();
+                if !matches!(self.state, UiState::Stopped) {
+                    self.update_queue.push(UiEvent::UpdatePreview(self.message.clone()));
+                }
                 self.message_timeout = Some(Box::pin(
                     tokio::time::sleep(std::time::Duration::from_secs(CONFIG.message_preview_timeout_secs))
                 ));
             },
+            SttMessageType::PartialResult => {
+                debug!("{}", msg);
+                match self.state {
+                    UiState::Recording | UiState::Processing if !msg.content.is_empty() => {
+                        self.update_queue.push(UiEvent::UpdatePartialPreview(msg.content));
+                    },
+                    _ => { /* Recording was discarded or already finished */ }
+                };
+            },
+        };
+    }
+
+    pub fn handle_pipeline_event(&mut self, event: PipelineEvent) {
+        match event {
+            PipelineEvent::RecordingStarted => {
+                if self.is_ready() {
+                    info!("Speech detected, started recording...");
+                    self.state = UiState::Recording;
+                    self.update_queue.push(UiEvent::UpdateState(self.state));
+                }
+            },
+            PipelineEvent::RecordingStopped => {
+                if let UiState::Recording = self.state {
+                    info!("Recording stopped automatically.");
+                    self.state = UiState::Processing;
+                    self.update_queue.push(UiEvent::UpdateState(self.state));
+                }
+            },
+            PipelineEvent::RecordingTimedOut => {
+                if let UiState::Recording = self.state {
+                    info!("Recording reached {}s timeout, stopped.", CONFIG.recording_timeout_secs);
+                    self.state = UiState::Processing;
+                    self.update_queue.push(UiEvent::UpdateState(self.state));
+                }
+            },
+            PipelineEvent::RecordingElapsed(secs) => {
+                if let UiState::Recording = self.state {
+                    let remaining = (CONFIG.recording_timeout_secs as u64).saturating_sub(secs);
+                    if self.countdown != Some(remaining) {
+                        self.countdown = Some(remaining);
+                        self.update_queue.push(UiEvent::UpdateCountdown(remaining));
+                    }
+                }
+            },
+            PipelineEvent::DeviceLost(_) => {
+                self.mic_lost = true;
+                if !matches!(self.state, UiState::Stopped) {
+                    if let UiState::Recording = self.state {
+                        self.restore_preview();
+                    }
+                    self.state = UiState::MicLost;
+                    self.update_queue.push(UiEvent::UpdateState(self.state));
+                }
+            },
+            PipelineEvent::DeviceRestored => {
+                self.mic_lost = false;
+                if let UiState::MicLost = self.state {
+                    self.state = UiState::Idle;
+                    self.update_queue.push(UiEvent::UpdateState(self.state));
+                }
+            },
+            PipelineEvent::InputLevel { rms, peak } => {
+                if let UiState::Recording = self.state {
+                    let level = (level_to_segments(rms), level_to_segments(peak));
+                    // Only send a button when the bar actually changes.
+                    if self.level != Some(level) {
+                        self.level = Some(level);
+                        self.update_queue.push(UiEvent::UpdateLevel { rms: level.0, peak: level.1 });
+                    }
+                }
+            },
+        }
+    }
+
+    /// A transcription came back without text. Only shown if still waiting for it,
+    /// the error state goes back to Idle by itself.
+    fn show_error(&mut self, text: &str) {
+        if let UiState::Processing = self.state {
+            self.enter_error(text);
+            self.restore_preview();
+        }
+    }
+
+    /// Shows `text` above the state button until the error state times out.
+    fn enter_error(&mut self, text: &str) {
+        self.state = UiState::Error;
+        self.update_queue.push(UiEvent::UpdateState(self.state));
+        self.update_queue.push(UiEvent::ShowError(text.to_string()));
+        self.error_timeout = Some(Box::pin(
+            tokio::time::sleep(std::time::Duration::from_secs(ERROR_TIMEOUT_SECS))
+        ));
+    }
+
+    /// A transcription came back, only leave Processing if nothing else happened in the meantime.
+    fn finish_processing(&mut self) {
+        if let UiState::Processing = self.state {
+            self.state = UiState::Idle;
+            self.update_queue.push(UiEvent::UpdateState(self.state));
+        }
+    }
+
+    async fn start_recording(&mut self, audio_pipeline: &mut AudioPipeline) {
+        info!("Started recording...");
+        self.state = UiState::Recording;
+        self.update_queue.push(UiEvent::UpdateState(self.state));
+        audio_pipeline.start_recording().await;
+    }
+
+    async fn stop_recording(&mut self, audio_pipeline: &mut AudioPipeline) {
+        info!("Stopped recording...");
+        self.state = UiState::Processing;
+        self.update_queue.push(UiEvent::UpdateState(self.state));
+        audio_pipeline.stop_recording_and_transcribe().await;
+    }
+
+    /// Throws away the recording or the transcription in progress and returns to Idle.
+    /// Returns false if there was nothing to cancel.
+    async fn cancel(&mut self, audio_pipeline: &mut AudioPipeline) -> bool {
+        match self.state {
+            UiState::Recording => {
+                info!("Cancelled recording.");
+                audio_pipeline.cancel_recording().await;
+            },
+            UiState::Processing => {
+                info!("Cancelled transcription.");
+                audio_pipeline.cancel_transcription();
+            },
+            _ => return false,
         };
+        self.state = UiState::Idle;
+        self.update_queue.push(UiEvent::UpdateState(self.state));
+        true
+    }
+
+    /// Put back whatever was in the preview before partial transcripts replaced it.
+    fn restore_preview(&mut self) {
+        if self.message.is_empty() {
+            self.update_queue.push(UiEvent::ClearPreview);
+        } else {
+            self.update_queue.push(UiEvent::UpdatePreview(self.message.clone()));
+        }
+    }
+
+    /// Maps a click on one of our buttons to the command it stands for.
+    fn button_action(&mut self, id: u8, right_click: bool) -> Option<InsimEvent> {
+        match (id, right_click) {
+            (STATE_ID, _) => Some(InsimEvent::ToggleRecording),
+            (CHANNEL_ID, false) => Some(InsimEvent::NextChannel),
+            (CHANNEL_ID, true) => Some(InsimEvent::PeviousChannel),
+            (PREVIEW_ID, false) => Some(InsimEvent::AcceptMessage),
+            (PREVIEW_ID, true) => {
+                if self.message.is_empty() || matches!(self.state, UiState::Stopped) {
+                    return None;
+                }
+                // The text entry would cut the message off and the rest would be lost.
+                if !fits_text_entry(&self.message) {
+                    info!("Message is too long to edit in game.");
+                    if self.is_ready() {
+                        self.enter_error("Too long to edit");
+                    }
+                    return None;
+                }
+
+                // LFS doesn't report clicks on text entry buttons, so editing needs its own mode.
+                self.editing = true;
+                self.update_queue.push(UiEvent::UpdatePreview(self.message.clone()));
+                None
+            },
+            _ => None,
+        }
     }
 
     pub async fn handle_insim_event(&mut self, event: InsimEvent, insim: InsimTask, audio_pipeline: &mut AudioPipeline) {
+        let event = match event {
+            InsimEvent::ButtonClicked { id, right_click } => match self.button_action(id, right_click) {
+                Some(event) => event,
+                None => return,
+            },
+            event => event,
+        };
+
         match event {
             InsimEvent::IsInGame(is_in_game) => {
                 if is_in_game {
                     match self.state {
                         UiState::Stopped => {
                             info!("Detected in-game state, starting STT.");
-                            self.state = UiState::Idle;
+                            self.state = if self.mic_lost { UiState::MicLost } else { UiState::Idle };
                             if !self.message.is_empty() {
                                 self.update_queue.push(UiEvent::UpdatePreview(self.message.clone()));
                             }
@@ -149,24 +430,48 @@ impl UiContext {
                 match self.state {
                     UiState::Processing => {},
                     UiState::Stopped => {},
-                    UiState::Idle => {
-                        info!("Started recording...");
-                        self.state = UiState::Recording;
-                        self.update_queue.push(UiEvent::UpdateState(self.state));
-                        audio_pipeline.start_recording().await;
-                    },
-                    UiState::Recording => {
-                        info!("Stopped recording...");
-                        self.state = UiState::Processing;
-                        self.update_queue.push(UiEvent::UpdateState(self.state));
-                        audio_pipeline.stop_recording_and_transcribe().await;
-                    },
+                    UiState::MicLost => {},
+                    UiState::Idle | UiState::Error => self.start_recording(audio_pipeline).await,
+                    UiState::Recording => self.stop_recording(audio_pipeline).await,
                 };
             },
+            InsimEvent::StartRecording => {
+                if self.is_ready() {
+                    self.start_recording(audio_pipeline).await;
+                }
+            },
+            InsimEvent::StopRecording => {
+                if let UiState::Recording = self.state {
+                    self.stop_recording(audio_pipeline).await;
+                }
+            },
+            InsimEvent::CancelRecording => {
+                if self.cancel(audio_pipeline).await {
+                    self.restore_preview();
+                }
+            },
+            InsimEvent::DiscardMessage => {
+                self.cancel(audio_pipeline).await;
+                if !self.message.is_empty() {
+                    info!("Discarded message.");
+                }
+                self.message.clear();
+                self.message_timeout = None;
+                if !matches!(self.state, UiState::Stopped) {
+                    self.update_queue.push(UiEvent::ClearPreview);
+                }
+            },
+            InsimEvent::ToggleHandsFree => {
+                self.hands_free = !self.hands_free;
+                info!("Hands-free mode {}.", if self.hands_free { "enabled" } else { "disabled" });
+                if !matches!(self.state, UiState::Stopped) {
+                    self.update_queue.push(UiEvent::UpdateState(self.state));
+                }
+            },
             InsimEvent::AcceptMessage => {
                 if self.message.is_empty() { return; }
 
-                if let UiState::Idle = self.state {
+                if self.is_ready() {
                     // Split message into chunks of MAX_MESSAGE_LEN and send each chunk as a separate Msx packet.
                     let mut messages: Vec<String> = self.message.chars()
                         .collect::<Vec<_>>()
@@ -192,10 +497,39 @@ impl UiContext {
                     self.message_timeout = None;
                 };
             },
+            InsimEvent::ButtonTypedIn(PREVIEW_ID, text) => {
+                // Only the final preview is editable, partial transcripts are replaced anyway.
+                if self.message.is_empty() || matches!(self.state, UiState::Stopped) {
+                    return;
+                }
+
+                // The preview was sent escaped, so the entered text comes back escaped as well.
+                let text = insim::core::string::colours::strip(text.as_str());
+                let text = insim::core::string::escaping::unescape(&text).trim().to_string();
+                if text.is_empty() {
+                    info!("Preview edited to empty, discarding message.");
+                    self.message.clear();
+                    self.message_timeout = None;
+                    self.update_queue.push(UiEvent::ClearPreview);
+                    return;
+                }
+
+                info!("Preview edited: {}", text);
+                self.message = text;
+                self.editing = false;
+                // Give the driver time to accept the corrected message.
+                self.message_timeout = Some(Box::pin(
+                    tokio::time::sleep(std::time::Duration::from_secs(CONFIG.message_preview_timeout_secs))
+                ));
+                self.update_queue.push(UiEvent::UpdatePreview(self.message.clone()));
+            },
+            InsimEvent::ButtonTypedIn(..) => { /* Not a text entry button */ },
+            InsimEvent::ButtonClicked { .. } => { /* Mapped to a command above */ },
             InsimEvent::NextChannel => {
                 let current_index = self.chat_channels.iter().position(|c| c == &self.active_channel).unwrap_or(0);
                 let next_index = (current_index + 1) % self.chat_channels.len();
                 self.active_channel = self.chat_channels[next_index].clone();
+                audio_pipeline.set_chat_channel(&self.active_channel);
                 self.update_queue.push(UiEvent::UpdateChannel(self.active_channel.clone()));
             },
             InsimEvent::PeviousChannel => {
@@ -206,17 +540,40 @@ impl UiContext {
                     current_index - 1
                 };
                 self.active_channel = self.chat_channels[previous_index].clone();
+                audio_pipeline.set_chat_channel(&self.active_channel);
                 self.update_queue.push(UiEvent::UpdateChannel(self.active_channel.clone()));
             },
         }
     }
 }
 
-fn get_state_btn(state: UiState) -> insim::insim::Btn {
+/// Waits for the timeout if one is set, forever otherwise.
+async fn wait_for(timeout: &mut Option<Pin<Box<Sleep>>>) {
+    match timeout {
+        Some(t) => t.as_mut().await,
+        None => std::future::pending().await,
+    }
+}
+
+fn get_del_btn(id: u8) -> insim::insim::Bfn {
+    insim::insim::Bfn {
+        subt: insim::insim::BfnType::DelBtn,
+        reqi: insim::identifiers::RequestId::from(1),
+        clickid: insim::identifiers::ClickId::from(CONFIG.btn_id_offset + id),
+        clickmax: 0,
+        ucid: insim::identifiers::ConnectionId::LOCAL,
+        ..Default::default()
+    }
+}
+
+fn get_state_btn(state: UiState, hands_free: bool) -> insim::insim::Btn {
     let text = match state {
+        UiState::Idle if hands_free => "^6•",
         UiState::Idle => "^2•",
         UiState::Recording => "^1•",
         UiState::Processing => "^3•",
+        UiState::MicLost => "^1x",
+        UiState::Error => "^1!",
         UiState::Stopped => "",
     };
 
@@ -231,7 +588,86 @@ fn get_state_btn(state: UiState) -> insim::insim::Btn {
         clickid: insim::identifiers::ClickId::from(CONFIG.btn_id_offset + STATE_ID),
         bstyle: insim::insim::BtnStyle{
             colour: insim::insim::BtnStyleColour::NotEditable,
-            flags: insim::insim::BtnStyleFlags::LIGHT,
+            flags: insim::insim::BtnStyleFlags::LIGHT | insim::insim::BtnStyleFlags::CLICK,
+        },
+        ..Default::default()
+    }
+}
+
+fn level_to_segments(level: f32) -> u8 {
+    let db = 20.0 * level.max(f32::EPSILON).log10();
+    let fraction = ((db - LEVEL_FLOOR_DB) / -LEVEL_FLOOR_DB).clamp(0.0, 1.0);
+    (fraction * LEVEL_SEGMENTS as f32).round() as u8
+}
+
+/// Level bar above the state dot, filled up to the RMS level with a marker at the peak.
+fn get_level_btn(rms: u8, peak: u8) -> insim::insim::Btn {
+    let text: String = (0..LEVEL_SEGMENTS)
+        .map(|i| {
+            let colour = match i {
+                i if i < rms && i < 6 => "^2",
+                i if i < rms && i < 8 => "^3",
+                i if i < rms => "^1",
+                i if i + 1 == peak => "^7",
+                _ => "^8",
+            };
+            format!("{}|", colour)
+        })
+        .collect();
+
+    insim::insim::Btn{
+        text: insim::core::string::escaping::escape(text.as_str()).to_string(),
+        t: CONFIG.ui_offset_top.saturating_sub(CONFIG.ui_scale),
+        w: CONFIG.ui_scale.saturating_mul(2),
+        h: CONFIG.ui_scale,
+        l: CONFIG.ui_offset_left,
+        reqi: insim::identifiers::RequestId::from(1),
+        ucid: insim::identifiers::ConnectionId::LOCAL,
+        clickid: insim::identifiers::ClickId::from(CONFIG.btn_id_offset + LEVEL_ID),
+        bstyle: insim::insim::BtnStyle{
+            colour: insim::insim::BtnStyleColour::NotEditable,
+            flags: insim::insim::BtnStyleFlags::LIGHT | insim::insim::BtnStyleFlags::LEFT,
+        },
+        ..Default::default()
+    }
+}
+
+/// Error text above the state dot, where the level bar is while recording.
+fn get_error_btn(text: String) -> insim::insim::Btn {
+    insim::insim::Btn{
+        text: insim::core::string::escaping::escape(text.as_str()).to_string(),
+        t: CONFIG.ui_offset_top.saturating_sub(CONFIG.ui_scale),
+        w: msg_to_btn_width(text.clone()),
+        h: CONFIG.ui_scale,
+        l: CONFIG.ui_offset_left,
+        reqi: insim::identifiers::RequestId::from(1),
+        ucid: insim::identifiers::ConnectionId::LOCAL,
+        clickid: insim::identifiers::ClickId::from(CONFIG.btn_id_offset + ERROR_ID),
+        bstyle: insim::insim::BtnStyle{
+            colour: insim::insim::BtnStyleColour::NotEditable,
+            flags: insim::insim::BtnStyleFlags::LIGHT | insim::insim::BtnStyleFlags::LEFT,
+        },
+        ..Default::default()
+    }
+}
+
+/// Remaining recording time, next to the level bar.
+fn get_countdown_btn(remaining: u64) -> insim::insim::Btn {
+    let colour = if remaining <= COUNTDOWN_WARNING_SECS { "^1" } else { "^7" };
+    let text = format!("{}{}s", colour, remaining);
+
+    insim::insim::Btn{
+        text: insim::core::string::escaping::escape(text.as_str()).to_string(),
+        t: CONFIG.ui_offset_top.saturating_sub(CONFIG.ui_scale),
+        w: CONFIG.ui_scale.saturating_mul(2),
+        h: CONFIG.ui_scale,
+        l: CONFIG.ui_offset_left.saturating_add(CONFIG.ui_scale.saturating_mul(2)), // next to the level bar
+        reqi: insim::identifiers::RequestId::from(1),
+        ucid: insim::identifiers::ConnectionId::LOCAL,
+        clickid: insim::identifiers::ClickId::from(CONFIG.btn_id_offset + COUNTDOWN_ID),
+        bstyle: insim::insim::BtnStyle{
+            colour: insim::insim::BtnStyleColour::NotEditable,
+            flags: insim::insim::BtnStyleFlags::LIGHT | insim::insim::BtnStyleFlags::LEFT,
         },
         ..Default::default()
     }
@@ -245,8 +681,20 @@ fn msg_to_btn_width(message: String) -> u8 {
     width.clamp(1, 200)
 }
 
-fn get_message_preview_btn(message: String) -> insim::insim::Btn {
-    let text = insim::core::string::escaping::escape(format!("^3{}", message).as_str()).to_string();
+/// Whether the message fits into the text entry dialog, which starts out with the escaped text.
+fn fits_text_entry(message: &str) -> bool {
+    insim::core::string::escaping::escape(message).len() <= MAX_MESSAGE_LEN
+}
+
+/// While editing, clicking opens LFS's text entry dialog and the result comes back as a Btt packet.
+fn get_message_preview_btn(message: String, mode: PreviewMode) -> insim::insim::Btn {
+    let (colour, typein, click) = match mode {
+        PreviewMode::Partial => ("^8", None, insim::insim::BtnStyleFlags::empty()),
+        PreviewMode::Final => ("^3", None, insim::insim::BtnStyleFlags::CLICK),
+        // No colour code, it would end up in the text entry.
+        PreviewMode::Editing => ("", Some(TYPEIN_INIT_WITH_TEXT | MAX_MESSAGE_LEN as u8), insim::insim::BtnStyleFlags::CLICK),
+    };
+    let text = insim::core::string::escaping::escape(format!("{}{}", colour, message).as_str()).to_string();
     insim::insim::Btn{
         text,
         t: CONFIG.ui_offset_top,
@@ -258,8 +706,9 @@ fn get_message_preview_btn(message: String) -> insim::insim::Btn {
         clickid: insim::identifiers::ClickId::from(CONFIG.btn_id_offset + PREVIEW_ID),
         bstyle: insim::insim::BtnStyle{
             colour: insim::insim::BtnStyleColour::NotEditable,
-            flags: insim::insim::BtnStyleFlags::LIGHT | insim::insim::BtnStyleFlags::LEFT,
+            flags: insim::insim::BtnStyleFlags::LIGHT | insim::insim::BtnStyleFlags::LEFT | click,
         },
+        typein,
         ..Default::default()
     }
 }
@@ -278,7 +727,7 @@ fn get_channel_btn(channel: ChatChannel) -> insim::insim::Btn {
         clickid: insim::identifiers::ClickId::from(CONFIG.btn_id_offset + CHANNEL_ID),
         bstyle: insim::insim::BtnStyle{
             colour: insim::insim::BtnStyleColour::NotEditable,
-            flags: insim::insim::BtnStyleFlags::LIGHT | insim::insim::BtnStyleFlags::LEFT,
+            flags: insim::insim::BtnStyleFlags::LIGHT | insim::insim::BtnStyleFlags::LEFT | insim::insim::BtnStyleFlags::CLICK,
         },
         ..Default::default()
     }